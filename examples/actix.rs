@@ -0,0 +1,33 @@
+use actix_web::{web, App, HttpResponse, HttpServer};
+use datadog_apm::actix::{DatadogMiddleware, ResourceName};
+use datadog_apm::{Client, Config};
+
+async fn get_user(id: web::Path<u64>) -> HttpResponse {
+    HttpResponse::Ok().body(format!("user {}", id))
+}
+
+#[actix_web::main]
+async fn main() -> std::io::Result<()> {
+    let client = Client::new(Config {
+        env: Some("production".to_string()),
+        service: "my-crate".to_string(),
+        ..Default::default()
+    });
+
+    let app_client = client.clone();
+    HttpServer::new(move || {
+        App::new()
+            .wrap(
+                DatadogMiddleware::new(app_client.clone())
+                    .resource_name(ResourceName::RoutePattern),
+            )
+            .route("/users/{id}", web::get().to(get_user))
+    })
+    .bind("127.0.0.1:8080")?
+    .run()
+    .await?;
+
+    // send the buffered traces before exiting
+    client.shutdown().await;
+    Ok(())
+}
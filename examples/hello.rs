@@ -1,5 +1,5 @@
 use datadog_apm::{Client, Config};
-use datadog_apm::{ErrorInfo, HttpInfo, Span, SqlInfo, Trace};
+use datadog_apm::{ErrorInfo, HttpInfo, Priority, Span, SpanType, SqlInfo, Trace};
 use std::collections::HashMap;
 use std::time::{Duration, SystemTime};
 
@@ -13,20 +13,24 @@ async fn main() {
 
     let trace = Trace {
         id: 123,
-        priority: 1,
+        priority: Priority::AutoKeep,
+        origin: None,
         spans: vec![
             Span {
                 id: 1,
                 parent_id: None,
                 name: "request".to_string(),
-                resource: "GET /path".to_string(),
-                r#type: "web".to_string(),
+                resource: "GET /path/:id".to_string(),
+                r#type: SpanType::Web,
                 start: SystemTime::now(),
                 duration: Duration::from_millis(50),
                 http: Some(HttpInfo {
                     url: String::from("/path/2?param=true"),
                     method: String::from("GET"),
                     status_code: String::from("500"),
+                    request_size: None,
+                    response_size: Some(21),
+                    route: Some(String::from("/path/:id")),
                 }),
                 error: Some(ErrorInfo {
                     r#type: "unknown".to_string(),
@@ -34,14 +38,21 @@ async fn main() {
                     stack: "stack here".to_string(),
                 }),
                 sql: None,
+                messaging: None,
+                cache: None,
                 tags: HashMap::new(),
+                metrics: HashMap::new(),
+                service: None,
+                measured: false,
+                analytics_rate: None,
+                links: Vec::new(),
             },
             Span {
                 id: 2,
                 parent_id: Some(1),
                 name: "database".to_string(),
                 resource: "select".to_string(),
-                r#type: "db".to_string(),
+                r#type: SpanType::Db,
                 start: SystemTime::now(),
                 duration: Duration::from_millis(20),
                 http: None,
@@ -51,14 +62,24 @@ async fn main() {
                     rows: "1".to_string(),
                     db: "test".to_string(),
                 }),
+                messaging: None,
+                cache: None,
                 tags: HashMap::new(),
+                metrics: HashMap::new(),
+                service: None,
+                measured: false,
+                analytics_rate: None,
+                links: Vec::new(),
             },
         ],
     };
 
-    client.send_trace(trace);
+    if let Err(err) = client.send_trace(trace) {
+        println!("trace not sent: {}", err);
+        return;
+    }
 
-    // wait for buffer flush
-    tokio::time::delay_for(Duration::from_secs(2)).await;
+    // send the buffered traces before exiting
+    client.shutdown().await;
     println!("trace sent");
 }
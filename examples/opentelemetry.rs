@@ -0,0 +1,30 @@
+use datadog_apm::opentelemetry::DatadogExporter;
+use datadog_apm::{Client, Config};
+use opentelemetry::trace::{Status, TraceContextExt, Tracer, TracerProvider as _};
+use opentelemetry::KeyValue;
+use opentelemetry_sdk::trace::TracerProvider;
+
+#[tokio::main]
+async fn main() {
+    let client = Client::new(Config {
+        env: Some("production".to_string()),
+        service: "my-crate".to_string(),
+        ..Default::default()
+    });
+
+    let provider = TracerProvider::builder()
+        .with_simple_exporter(DatadogExporter::new(client.clone()))
+        .build();
+    let tracer = provider.tracer("my-crate");
+
+    tracer.in_span("GET /users/:id", |cx| {
+        cx.span().set_attribute(KeyValue::new("http.method", "GET"));
+        tracer.in_span("find user", |cx| {
+            cx.span().set_status(Status::error("user not found"));
+        });
+    });
+
+    // send the buffered traces before exiting
+    drop(provider);
+    client.shutdown().await;
+}
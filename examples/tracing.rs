@@ -0,0 +1,44 @@
+use datadog_apm::tracing::DatadogLayer;
+use datadog_apm::{Client, Config};
+use tracing::instrument;
+use tracing_subscriber::layer::SubscriberExt;
+
+#[instrument(fields(span.type = "db", sql.query = "select * from users where id = $1", sql.db = "test"))]
+async fn find_user(id: u64) -> Option<String> {
+    tokio::time::delay_for(std::time::Duration::from_millis(20)).await;
+    Some(format!("user {}", id))
+}
+
+#[instrument(fields(
+    resource = "GET /users/:id",
+    span.type = "web",
+    http.method = "GET",
+    http.url = %path,
+    http.status_code
+))]
+async fn get_user(path: String, id: u64) {
+    let status_code = match find_user(id).await {
+        Some(_) => "200",
+        None => {
+            tracing::error!(error.type = "not_found", "user {} not found", id);
+            "404"
+        }
+    };
+    tracing::Span::current().record("http.status_code", status_code);
+}
+
+#[tokio::main]
+async fn main() {
+    let client = Client::new(Config {
+        env: Some("production".to_string()),
+        service: "my-crate".to_string(),
+        ..Default::default()
+    });
+
+    let subscriber = tracing_subscriber::registry().with(DatadogLayer::new(client.clone()));
+    tracing::subscriber::set_global_default(subscriber).unwrap();
+
+    get_user("/users/1".to_string(), 1).await;
+
+    client.shutdown().await;
+}
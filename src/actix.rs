@@ -0,0 +1,301 @@
+//! [actix-web](https://actix.rs) integration, enabled with the `actix` feature.
+//!
+//! Wrap an `App` with a [`DatadogMiddleware`](struct.DatadogMiddleware.html) and every request is sent
+//! as a trace with a single `web` span:
+//! ```no_run
+//! use actix_web::{web, App, HttpServer};
+//! use datadog_apm::actix::DatadogMiddleware;
+//! use datadog_apm::{Client, Config};
+//!
+//! # async fn run() -> std::io::Result<()> {
+//! let client = Client::new(Config {
+//!     service: "my-crate".to_string(),
+//!     ..Default::default()
+//! });
+//! HttpServer::new(move || {
+//!     App::new()
+//!         .wrap(DatadogMiddleware::new(client.clone()))
+//!         .route("/users/{id}", web::get().to(|| async { "user" }))
+//! })
+//! .bind("127.0.0.1:8080")?
+//! .run()
+//! .await
+//! # }
+//! ```
+//!
+//! A request carrying the datadog [propagation](../propagation/index.html) headers continues the trace of the caller.
+//! Responses with a `5xx` status, and handler errors, set the [`ErrorInfo`](../struct.ErrorInfo.html) of the span.
+
+use crate::client::{Client, ErrorInfo, HttpInfo, Priority, Span, SpanType, Trace};
+use crate::clock::Clock;
+use crate::propagation::TraceContext;
+
+use actix_web::dev::{BodySize, MessageBody, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::header::CONTENT_LENGTH;
+use actix_web::http::StatusCode;
+use actix_web::Error;
+use hyper::HeaderMap;
+
+use std::future::{ready, Future, Ready};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// How the resource of the request spans is named.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ResourceName {
+    /// The method and the matched route pattern, like `GET /users/{id}`, falling back to the path
+    /// when no route matches. It keeps the number of resources low, the default.
+    #[default]
+    RoutePattern,
+    /// The method and the raw request path, like `GET /users/1`.
+    Path,
+}
+
+/// An actix-web middleware sending a trace to datadog through a [`Client`](../struct.Client.html) for every request.
+#[derive(Debug, Clone)]
+pub struct DatadogMiddleware {
+    client: Client,
+    resource_name: ResourceName,
+}
+
+impl DatadogMiddleware {
+    pub fn new(client: Client) -> DatadogMiddleware {
+        DatadogMiddleware {
+            client,
+            resource_name: ResourceName::default(),
+        }
+    }
+
+    /// How the resource of the request spans is named, defaults to `ResourceName::RoutePattern`.
+    pub fn resource_name(mut self, resource_name: ResourceName) -> Self {
+        self.resource_name = resource_name;
+        self
+    }
+}
+
+impl<S, B> Transform<S> for DatadogMiddleware
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = DatadogService<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(DatadogService {
+            service,
+            client: self.client.clone(),
+            resource_name: self.resource_name,
+        }))
+    }
+}
+
+/// The service created by [`DatadogMiddleware`](struct.DatadogMiddleware.html), wrapping the service of the app.
+#[derive(Debug)]
+pub struct DatadogService<S> {
+    service: S,
+    client: Client,
+    resource_name: ResourceName,
+}
+
+impl<S, B> Service for DatadogService<S>
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: MessageBody + 'static,
+{
+    type Request = ServiceRequest;
+    type Response = ServiceResponse<B>;
+    type Error = Error;
+    #[allow(clippy::type_complexity)]
+    type Future = Pin<Box<dyn Future<Output = Result<ServiceResponse<B>, Error>>>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.service.poll_ready(cx)
+    }
+
+    fn call(&mut self, request: ServiceRequest) -> Self::Future {
+        let (mut trace, mut span) = start_trace(&request, self.resource_name, self.client.clock());
+        let client = self.client.clone();
+        let response = self.service.call(request);
+
+        Box::pin(async move {
+            let response = response.await;
+            span.finish_with(client.clock());
+            match &response {
+                Ok(response) => {
+                    let status = response.status();
+                    if status.is_server_error() {
+                        span.error = Some(ErrorInfo {
+                            r#type: "http_error".to_string(),
+                            msg: status.to_string(),
+                            stack: String::new(),
+                        });
+                    }
+                    set_status(&mut span, status);
+                    if let Some(http) = &mut span.http {
+                        http.response_size = match response.response().body().size() {
+                            BodySize::Sized(size) => Some(size),
+                            BodySize::Empty => Some(0),
+                            BodySize::None | BodySize::Stream => None,
+                        };
+                    }
+                }
+                Err(error) => {
+                    span.error = Some(ErrorInfo::from_error(error));
+                    set_status(&mut span, error.as_response_error().status_code());
+                }
+            }
+            trace.spans.push(span);
+            let _ = client.send_trace(trace);
+            response
+        })
+    }
+}
+
+/// Starts the trace of the request, continuing the trace of the caller when the request has its context.
+fn start_trace(
+    request: &ServiceRequest,
+    resource_name: ResourceName,
+    clock: &dyn Clock,
+) -> (Trace, Span) {
+    let route = request.match_pattern();
+    let resource = match (resource_name, &route) {
+        (ResourceName::RoutePattern, Some(route)) => route.clone(),
+        _ => request.path().to_string(),
+    };
+    let mut span = Span::builder(
+        "actix-web.request",
+        format!("{} {}", request.method(), resource),
+    )
+    .r#type(SpanType::Web)
+    .http(HttpInfo {
+        url: request.uri().to_string(),
+        status_code: String::new(),
+        method: request.method().to_string(),
+        request_size: request
+            .headers()
+            .get(CONTENT_LENGTH)
+            .and_then(|len| len.to_str().ok()?.parse().ok()),
+        response_size: None,
+        route,
+    })
+    .build_with(clock);
+
+    // actix-web has its own header map type
+    let headers = request
+        .headers()
+        .iter()
+        .map(|(name, value)| (name.clone(), value.clone()))
+        .collect::<HeaderMap>();
+    let trace = match TraceContext::from_headers(&headers) {
+        Some(context) => {
+            span.parent_id = Some(context.parent_id);
+            let mut trace = Trace::new(context.priority.unwrap_or(Priority::AutoKeep));
+            trace.id = context.trace_id;
+            trace
+        }
+        None => Trace::new(Priority::AutoKeep),
+    };
+    (trace, span)
+}
+
+fn set_status(span: &mut Span, status: StatusCode) {
+    if let Some(http) = &mut span.http {
+        http.status_code = status.as_u16().to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Config;
+    use crate::propagation::{PARENT_ID_HEADER, TAGS_HEADER, TRACE_ID_HEADER};
+    use crate::testing::recording_client;
+
+    use actix_web::{test, web, App, HttpResponse};
+
+    #[actix_rt::test]
+    async fn test_request_span() {
+        let (client, connector) = recording_client(Config::default());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(DatadogMiddleware::new(client.clone()))
+                .route("/users/{id}", web::get().to(|| async { "user" })),
+        )
+        .await;
+
+        let request = test::TestRequest::get().uri("/users/1?page=2").to_request();
+        let response = test::call_service(&mut app, request).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        client.flush().await;
+
+        let traces = connector.traces();
+        assert_eq!(traces.len(), 1);
+        let span = &traces[0][0];
+        assert_eq!(span["name"], "actix-web.request");
+        assert_eq!(span["resource"], "GET /users/{id}");
+        assert_eq!(span["type"], "web");
+        assert_eq!(span["parent_id"], serde_json::Value::Null);
+        assert_eq!(span["error"], 0);
+        let meta = &span["meta"];
+        assert_eq!(meta["http.method"], "GET");
+        assert_eq!(meta["http.url"], "/users/1?page=2");
+        assert_eq!(meta["http.status_code"], "200");
+        assert_eq!(meta.get("http.request.content_length"), None);
+        assert_eq!(meta["http.response.content_length"], "4");
+        assert_eq!(meta["http.route"], "/users/{id}");
+    }
+
+    #[actix_rt::test]
+    async fn test_resource_name_and_errors() {
+        let (client, connector) = recording_client(Config::default());
+        let mut app = test::init_service(
+            App::new()
+                .wrap(DatadogMiddleware::new(client.clone()).resource_name(ResourceName::Path))
+                .route(
+                    "/users/{id}",
+                    web::get().to(HttpResponse::InternalServerError),
+                ),
+        )
+        .await;
+
+        let request = test::TestRequest::get()
+            .uri("/users/1")
+            .header(TRACE_ID_HEADER, "123")
+            .header(PARENT_ID_HEADER, "456")
+            .header(TAGS_HEADER, "_dd.p.tid=0000000000000001")
+            .to_request();
+        test::call_service(&mut app, request).await;
+        let request = test::TestRequest::post()
+            .uri("/missing")
+            .header(CONTENT_LENGTH, "5")
+            .set_payload("hello")
+            .to_request();
+        test::call_service(&mut app, request).await;
+        client.flush().await;
+
+        let traces = connector.traces();
+        assert_eq!(traces.len(), 2);
+        let span = &traces[0][0];
+        assert_eq!(span["trace_id"], 123);
+        assert_eq!(span["meta"]["_dd.p.tid"], "0000000000000001");
+        assert_eq!(span["resource"], "GET /users/1");
+        assert_eq!(span["meta"]["http.route"], "/users/{id}");
+        assert_eq!(span["parent_id"], 456);
+        assert_eq!(span["meta"]["http.status_code"], "500");
+        assert_eq!(span["error"], 1);
+
+        let span = &traces[1][0];
+        assert_eq!(span["resource"], "POST /missing");
+        assert_eq!(span["meta"]["http.status_code"], "404");
+        assert_eq!(span["meta"]["http.request.content_length"], "5");
+        assert_eq!(span["meta"].get("http.route"), None);
+        assert_eq!(span["error"], 0);
+    }
+}
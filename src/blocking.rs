@@ -0,0 +1,206 @@
+//! A client for synchronous code, enabled with the `blocking` feature.
+//!
+//! The [`BlockingClient`](struct.BlockingClient.html) runs a [`Client`](../struct.Client.html)
+//! on its own tokio runtime, so it does not need a runtime to be running:
+//! ```no_run
+//! use datadog_apm::blocking::BlockingClient;
+//! use datadog_apm::{Config, Priority, Span, Trace};
+//!
+//! let client = BlockingClient::new(Config {
+//!     service: "my-script".to_string(),
+//!     ..Default::default()
+//! });
+//!
+//! let mut trace = Trace::new(Priority::AutoKeep);
+//! trace.spans.push(Span::new("script", "import users"));
+//! client.send_trace(trace).unwrap();
+//! client.flush();
+//! ```
+
+use crate::client::{Client, ClientStats, Config, Trace};
+use crate::error::{ConfigError, TraceError};
+
+use tokio::sync::mpsc;
+
+use std::sync::mpsc as std_mpsc;
+use std::thread::JoinHandle;
+
+/// A [`Client`](../struct.Client.html) that can be used without a tokio runtime.
+///
+/// It spawns a thread running a tokio runtime for its buffer consumer task, so it should be created once and reused.
+/// Dropping it sends the buffered traces and stops the thread, blocking until they are sent.
+#[derive(Debug)]
+pub struct BlockingClient {
+    client: Client,
+    commands: Option<mpsc::UnboundedSender<Command>>,
+    runtime_thread: Option<JoinHandle<()>>,
+}
+
+#[derive(Debug)]
+enum Command {
+    Flush(std_mpsc::Sender<()>),
+}
+
+impl BlockingClient {
+    /// Creates the client and spawns the thread running it.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the config is invalid, use [`BlockingClient::try_new`](#method.try_new) to handle the error instead.
+    pub fn new(config: Config) -> BlockingClient {
+        match BlockingClient::try_new(config) {
+            Ok(client) => client,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates the client and spawns the thread running it, failing if the config is invalid.
+    pub fn try_new(config: Config) -> Result<BlockingClient, ConfigError> {
+        let (client_sender, client_receiver) = std_mpsc::channel();
+        let (commands, mut command_receiver) = mpsc::unbounded_channel();
+
+        let runtime_thread = std::thread::Builder::new()
+            .name("datadog-apm".to_string())
+            .spawn(move || {
+                let mut runtime = tokio::runtime::Builder::new()
+                    .basic_scheduler()
+                    .enable_all()
+                    .build()
+                    .expect("could not build the datadog apm client runtime");
+                runtime.block_on(async move {
+                    let client = match Client::try_new(config) {
+                        Ok(client) => client,
+                        Err(err) => {
+                            let _ = client_sender.send(Err(err));
+                            return;
+                        }
+                    };
+                    let _ = client_sender.send(Ok(client.clone()));
+                    // runs until the blocking client is dropped
+                    while let Some(command) = command_receiver.recv().await {
+                        match command {
+                            Command::Flush(done) => {
+                                client.flush().await;
+                                let _ = done.send(());
+                            }
+                        }
+                    }
+                    client.shutdown().await;
+                });
+            })
+            .expect("could not spawn the datadog apm client thread");
+
+        let client = client_receiver
+            .recv()
+            .expect("the datadog apm client thread stopped")?;
+        Ok(BlockingClient {
+            client,
+            commands: Some(commands),
+            runtime_thread: Some(runtime_thread),
+        })
+    }
+
+    /// Enqueues the trace to be sent, see [`Client::send_trace`](../struct.Client.html#method.send_trace).
+    pub fn send_trace(&self, trace: Trace) -> Result<(), TraceError> {
+        self.client.send_trace(trace)
+    }
+
+    /// Sends all the traces enqueued so far, blocking until the requests to the datadog agent complete.
+    pub fn flush(&self) {
+        let (done, wait) = std_mpsc::channel();
+        if let Some(commands) = &self.commands {
+            if commands.send(Command::Flush(done)).is_ok() {
+                let _ = wait.recv();
+            }
+        }
+    }
+
+    /// Returns the counters of the traces handled by the client, see [`Client::stats`](../struct.Client.html#method.stats).
+    pub fn stats(&self) -> ClientStats {
+        self.client.stats()
+    }
+}
+
+impl Drop for BlockingClient {
+    fn drop(&mut self) {
+        // closing the commands channel shuts the client down
+        self.commands.take();
+        if let Some(runtime_thread) = self.runtime_thread.take() {
+            let _ = runtime_thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Priority, Span};
+
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    /// Spawns a fake datadog agent on a thread, returning its url and a receiver of the request lines.
+    fn spawn_agent() -> (String, std_mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let agent_url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, receiver) = std_mpsc::channel();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut reader = BufReader::new(stream.unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut content_length = 0;
+                loop {
+                    let mut header = String::new();
+                    reader.read_line(&mut header).unwrap();
+                    if header == "\r\n" {
+                        break;
+                    }
+                    if let Some(len) = header.to_lowercase().strip_prefix("content-length:") {
+                        content_length = len.trim().parse().unwrap();
+                    }
+                }
+                let mut body = vec![0; content_length];
+                reader.read_exact(&mut body).unwrap();
+                reader
+                    .get_mut()
+                    .write_all(
+                        b"HTTP/1.1 200 OK\r\ncontent-length: 2\r\nconnection: close\r\n\r\nOK",
+                    )
+                    .unwrap();
+                sender.send(request_line).unwrap();
+            }
+        });
+        (agent_url, receiver)
+    }
+
+    #[test]
+    fn test_send_trace_without_runtime() {
+        let (agent_url, requests) = spawn_agent();
+        let client = BlockingClient::new(Config {
+            agent_url: Some(agent_url),
+            ..Default::default()
+        });
+
+        let mut trace = Trace::new(Priority::AutoKeep);
+        trace.spans.push(Span::new("request", "/home"));
+        client.send_trace(trace).unwrap();
+        client.flush();
+
+        assert_eq!(client.stats().sent, 1);
+        assert_eq!(
+            requests.try_recv().unwrap(),
+            "POST /v0.5/traces HTTP/1.1\r\n"
+        );
+        drop(client);
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        let result = BlockingClient::try_new(Config {
+            agent_url: Some("ftp://dd-agent".to_string()),
+            ..Default::default()
+        });
+        assert!(matches!(result, Err(ConfigError::InvalidAgentUrl(_))));
+    }
+}
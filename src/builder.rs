@@ -0,0 +1,347 @@
+use crate::client::{
+    CacheInfo, ErrorInfo, HttpInfo, MessagingInfo, Span, SpanLink, SpanType, SqlInfo,
+};
+use crate::clock::{Clock, SystemClock};
+use crate::id::random_id;
+
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+impl Span {
+    /// Creates a span with a random id, starting now.
+    pub fn new(name: impl Into<String>, resource: impl Into<String>) -> Span {
+        Span::builder(name, resource).build()
+    }
+
+    /// Sets the duration as the time elapsed since the span start, zero when it starts in the future.
+    ///
+    /// Timing a span created with `Span::new`, or built without a `start`:
+    /// ```
+    /// use datadog_apm::Span;
+    ///
+    /// let mut span = Span::new("request", "GET /path");
+    /// // handle the request
+    /// span.finish();
+    /// ```
+    pub fn finish(&mut self) {
+        self.finish_with(&SystemClock)
+    }
+
+    /// Like [`finish`](#method.finish), reading the current time from `clock`.
+    pub(crate) fn finish_with(&mut self, clock: &dyn Clock) {
+        self.duration = clock.now().duration_since(self.start).unwrap_or_default();
+    }
+
+    /// Marks the span as failed with only a message, setting an [`ErrorInfo`](struct.ErrorInfo.html)
+    /// with an empty type and stack. It replaces the previous error of the span.
+    pub fn mark_error(&mut self, msg: impl Into<String>) {
+        self.error = Some(ErrorInfo {
+            r#type: String::new(),
+            msg: msg.into(),
+            stack: String::new(),
+        });
+    }
+
+    /// Starts building a span with the given name and resource.
+    pub fn builder(name: impl Into<String>, resource: impl Into<String>) -> SpanBuilder {
+        SpanBuilder {
+            id: random_id(),
+            parent_id: None,
+            name: name.into(),
+            resource: resource.into(),
+            r#type: SpanType::from("custom"),
+            start: None,
+            duration: Duration::from_secs(0),
+            end: None,
+            http: None,
+            error: None,
+            sql: None,
+            messaging: None,
+            cache: None,
+            tags: HashMap::new(),
+            metrics: HashMap::new(),
+            service: None,
+            measured: false,
+            analytics_rate: None,
+            links: Vec::new(),
+        }
+    }
+}
+
+/// Builds a [`Span`](struct.Span.html), defaulting the optional fields to `None` or empty.
+///
+/// The span for a http request and the child-span for its sql transaction:
+/// ```
+/// use datadog_apm::{ErrorInfo, HttpInfo, Span, SqlInfo};
+/// use std::time::Duration;
+///
+/// let request = Span::builder("request", "GET /path/:id")
+///     .id(1)
+///     .r#type("web")
+///     .duration(Duration::from_millis(50))
+///     .http(HttpInfo {
+///         url: String::from("/path/2?param=true"),
+///         method: String::from("GET"),
+///         status_code: String::from("500"),
+///         request_size: None,
+///         response_size: Some(21),
+///         route: Some(String::from("/path/:id")),
+///     })
+///     .error(ErrorInfo {
+///         r#type: "unknown".to_string(),
+///         msg: "Internal error".to_string(),
+///         stack: "stack here".to_string(),
+///     })
+///     .build();
+///
+/// let database = Span::builder("database", "select")
+///     .id(2)
+///     .parent_id(1)
+///     .r#type("db")
+///     .duration(Duration::from_millis(20))
+///     .sql(SqlInfo {
+///         query: "select 1".to_string(),
+///         rows: "1".to_string(),
+///         db: "test".to_string(),
+///     })
+///     .tag("db.instance", "primary")
+///     .build();
+/// ```
+#[derive(Debug, Clone)]
+pub struct SpanBuilder {
+    id: u64,
+    parent_id: Option<u64>,
+    name: String,
+    resource: String,
+    r#type: SpanType,
+    start: Option<SystemTime>,
+    duration: Duration,
+    end: Option<SystemTime>,
+    http: Option<HttpInfo>,
+    error: Option<ErrorInfo>,
+    sql: Option<SqlInfo>,
+    messaging: Option<MessagingInfo>,
+    cache: Option<CacheInfo>,
+    tags: HashMap<String, String>,
+    metrics: HashMap<String, f64>,
+    service: Option<String>,
+    measured: bool,
+    analytics_rate: Option<f64>,
+    links: Vec<SpanLink>,
+}
+
+impl SpanBuilder {
+    /// Span id, defaults to a random id.
+    pub fn id(mut self, id: u64) -> Self {
+        self.id = id;
+        self
+    }
+
+    pub fn parent_id(mut self, parent_id: u64) -> Self {
+        self.parent_id = Some(parent_id);
+        self
+    }
+
+    /// Replaces the resource given to `Span::builder`.
+    pub fn resource(mut self, resource: impl Into<String>) -> Self {
+        self.resource = resource.into();
+        self
+    }
+
+    /// Span type, like `web` or `db`, defaults to `custom`.
+    pub fn r#type(mut self, r#type: impl Into<SpanType>) -> Self {
+        self.r#type = r#type.into();
+        self
+    }
+
+    /// Span start, defaults to the time `build` is called.
+    pub fn start(mut self, start: SystemTime) -> Self {
+        self.start = Some(start);
+        self
+    }
+
+    pub fn duration(mut self, duration: Duration) -> Self {
+        self.duration = duration;
+        self.end = None;
+        self
+    }
+
+    /// Sets the duration as the time between the span start and `end`, replacing `duration`.
+    /// An `end` before the start results in a zero duration.
+    pub fn end(mut self, end: SystemTime) -> Self {
+        self.end = Some(end);
+        self
+    }
+
+    pub fn http(mut self, http: HttpInfo) -> Self {
+        self.http = Some(http);
+        self
+    }
+
+    pub fn error(mut self, error: ErrorInfo) -> Self {
+        self.error = Some(error);
+        self
+    }
+
+    pub fn sql(mut self, sql: SqlInfo) -> Self {
+        self.sql = Some(sql);
+        self
+    }
+
+    pub fn messaging(mut self, messaging: MessagingInfo) -> Self {
+        self.messaging = Some(messaging);
+        self
+    }
+
+    pub fn cache(mut self, cache: CacheInfo) -> Self {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Adds a tag, replacing any previous value of the same key.
+    pub fn tag(mut self, key: impl Into<String>, value: impl Into<String>) -> Self {
+        self.tags.insert(key.into(), value.into());
+        self
+    }
+
+    /// Adds a metric, replacing any previous value of the same key.
+    pub fn metric(mut self, key: impl Into<String>, value: f64) -> Self {
+        self.metrics.insert(key.into(), value);
+        self
+    }
+
+    /// Overrides the client service for this span.
+    pub fn service(mut self, service: impl Into<String>) -> Self {
+        self.service = Some(service.into());
+        self
+    }
+
+    /// Computes the trace metrics of this span, see [`Span::measured`](struct.Span.html#structfield.measured).
+    pub fn measured(mut self, measured: bool) -> Self {
+        self.measured = measured;
+        self
+    }
+
+    /// Keeps the span for App Analytics at this rate, see [`Span::analytics_rate`](struct.Span.html#structfield.analytics_rate).
+    pub fn analytics_rate(mut self, rate: f64) -> Self {
+        self.analytics_rate = Some(rate);
+        self
+    }
+
+    /// Links the span to a span of another trace.
+    pub fn link(mut self, link: SpanLink) -> Self {
+        self.links.push(link);
+        self
+    }
+
+    pub fn build(self) -> Span {
+        self.build_with(&SystemClock)
+    }
+
+    /// Like [`build`](#method.build), starting the span at the current time of `clock` when it has no `start`.
+    pub(crate) fn build_with(self, clock: &dyn Clock) -> Span {
+        let start = self.start.unwrap_or_else(|| clock.now());
+        let duration = match self.end {
+            Some(end) => end.duration_since(start).unwrap_or_default(),
+            None => self.duration,
+        };
+        Span {
+            id: self.id,
+            parent_id: self.parent_id,
+            name: self.name,
+            resource: self.resource,
+            r#type: self.r#type,
+            start,
+            duration,
+            http: self.http,
+            error: self.error,
+            sql: self.sql,
+            messaging: self.messaging,
+            cache: self.cache,
+            tags: self.tags,
+            metrics: self.metrics,
+            service: self.service,
+            measured: self.measured,
+            analytics_rate: self.analytics_rate,
+            links: self.links,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::{Priority, Trace};
+
+    #[test]
+    fn test_builder_defaults() {
+        let span = Span::builder("request", "/home").build();
+
+        assert_eq!(span.r#type, SpanType::Custom("custom".to_string()));
+        assert_eq!(span.parent_id, None);
+        assert!(span.http.is_none() && span.error.is_none() && span.sql.is_none());
+        assert!(span.tags.is_empty());
+        assert_eq!(span.duration, Duration::from_secs(0));
+        assert_eq!(span.analytics_rate, None);
+
+        let span = Span::builder("request", "/home")
+            .resource("/about")
+            .start(SystemTime::UNIX_EPOCH)
+            .analytics_rate(0.25)
+            .build();
+        assert_eq!(span.resource, "/about");
+        assert_eq!(span.analytics_rate, Some(0.25));
+        assert_eq!(span.start, SystemTime::UNIX_EPOCH);
+    }
+
+    #[test]
+    fn test_builder_end() {
+        let start = SystemTime::now();
+        let span = Span::builder("request", "/home")
+            .start(start)
+            .end(start + Duration::from_millis(30))
+            .build();
+        assert_eq!(span.duration, Duration::from_millis(30));
+    }
+
+    #[test]
+    fn test_builder_end_before_start() {
+        let start = SystemTime::now();
+        let span = Span::builder("request", "/home")
+            .start(start)
+            .end(start - Duration::from_millis(30))
+            .build();
+        assert_eq!(span.duration, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_finish() {
+        let mut span = Span::builder("request", "/home")
+            .start(SystemTime::now() - Duration::from_secs(1))
+            .build();
+        span.finish();
+        assert!(span.duration >= Duration::from_secs(1));
+
+        let mut span = Span::builder("request", "/home")
+            .start(SystemTime::now() + Duration::from_secs(60))
+            .duration(Duration::from_secs(1))
+            .build();
+        span.finish();
+        assert_eq!(span.duration, Duration::from_secs(0));
+    }
+
+    #[test]
+    fn test_new_generates_random_ids() {
+        let first = Span::new("request", "/home");
+        let second = Span::new("request", "/home");
+
+        assert_ne!(first.id, second.id);
+        assert_ne!(
+            Trace::new(Priority::AutoKeep).id,
+            Trace::new(Priority::AutoKeep).id
+        );
+        for id in &[first.id, second.id] {
+            assert!(*id > 0 && *id < 1 << 63);
+        }
+    }
+}
@@ -1,120 +1,1090 @@
-use hyper::{Body, Method, Request};
+use crate::clock::{Clock, SystemClock};
+use crate::connector::{AgentConnector, CustomConnector};
+use crate::container::container_id;
+use crate::error::{ConfigError, TraceError};
+use crate::id::random_id;
+use hyper::body::Bytes;
+use hyper::header::RETRY_AFTER;
+use hyper::{Body, Method, Request, Response, StatusCode, Uri};
 
-use hyper::client::connect::HttpConnector;
+use flate2::write::{GzEncoder, ZlibEncoder};
+use hyper::client::connect::{Connection, HttpConnector};
+use hyper::service::Service;
 use rmp::encode;
-use serde::Serialize;
-use tokio::sync::mpsc;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc::{
+    self,
+    error::{SendError, TrySendError},
+};
+use tokio::sync::oneshot;
+use tokio::task::JoinHandle;
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+use std::future::Future;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-#[derive(Debug, Clone)]
+/// Callback invoked with every dropped trace.
+pub type DropCallback = Arc<dyn Fn(&Trace) + Send + Sync>;
+
+#[derive(Clone)]
 pub struct Client {
+    enabled: bool,
     env: Option<String>,
-    endpoint: String,
+    version: Option<String>,
+    hostname: Option<String>,
+    endpoint: Arc<Mutex<Endpoint>>,
+    agent_base_urls: Arc<Vec<String>>,
     service: String,
-    http_client: hyper::Client<HttpConnector>,
+    global_tags: HashMap<String, String>,
+    http_client: hyper::Client<AgentConnector>,
     buffer_sender: mpsc::Sender<Trace>,
+    control_sender: mpsc::UnboundedSender<Control>,
+    consumer_task: Arc<Mutex<Option<JoinHandle<()>>>>,
+    buffer_queue_capacity: usize,
     buffer_size: usize,
+    max_payload_bytes: usize,
     buffer_flush_max_interval: Duration,
+    flush_on_error: bool,
+    max_retries: u32,
+    retry_backoff: Duration,
+    retry_backoff_limit: Duration,
+    request_timeout: Duration,
+    compression: Compression,
+    validate_traces: bool,
+    sample_rate: f64,
+    analytics_sample_rate: Option<f64>,
+    obfuscate_sql: bool,
+    max_tag_value_len: usize,
+    container_id: Option<String>,
+    sampling_rates: Arc<Mutex<HashMap<String, f64>>>,
+    on_drop: Option<DropCallback>,
+    stats: Arc<Stats>,
+    clock: Arc<dyn Clock>,
+}
+
+impl fmt::Debug for Client {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("enabled", &self.enabled)
+            .field("env", &self.env)
+            .field("version", &self.version)
+            .field("hostname", &self.hostname)
+            .field("endpoint", &self.endpoint)
+            .field("agent_base_urls", &self.agent_base_urls)
+            .field("service", &self.service)
+            .field("global_tags", &self.global_tags)
+            .field("http_client", &self.http_client)
+            .field("buffer_sender", &self.buffer_sender)
+            .field("control_sender", &self.control_sender)
+            .field("consumer_task", &self.consumer_task)
+            .field("buffer_queue_capacity", &self.buffer_queue_capacity)
+            .field("buffer_size", &self.buffer_size)
+            .field("max_payload_bytes", &self.max_payload_bytes)
+            .field("buffer_flush_max_interval", &self.buffer_flush_max_interval)
+            .field("flush_on_error", &self.flush_on_error)
+            .field("max_retries", &self.max_retries)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("retry_backoff_limit", &self.retry_backoff_limit)
+            .field("request_timeout", &self.request_timeout)
+            .field("compression", &self.compression)
+            .field("validate_traces", &self.validate_traces)
+            .field("sample_rate", &self.sample_rate)
+            .field("analytics_sample_rate", &self.analytics_sample_rate)
+            .field("obfuscate_sql", &self.obfuscate_sql)
+            .field("max_tag_value_len", &self.max_tag_value_len)
+            .field("container_id", &self.container_id)
+            .field("sampling_rates", &self.sampling_rates)
+            .field("on_drop", &self.on_drop.as_ref().map(|_| "Fn(&Trace)"))
+            .field("stats", &self.stats())
+            .field("clock", &self.clock)
+            .finish()
+    }
+}
+
+/// Counters of the traces handled by a client and all of its clones, returned by [`Client::stats`](struct.Client.html#method.stats).
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ClientStats {
+    /// Traces accepted by `send_trace`.
+    pub enqueued: u64,
+    /// Traces the datadog agent accepted.
+    pub sent: u64,
+    /// Traces dropped, either because the buffer queue was full or because sending them failed.
+    pub dropped: u64,
+    /// Failed requests to the datadog agent, including the ones that are retried.
+    pub send_errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    /// Traces in the buffer queue, incremented before `try_send` so the consumer task never takes it below 0.
+    queued: AtomicUsize,
+    enqueued: AtomicU64,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+    send_errors: AtomicU64,
+    /// Whether the buffer consumer task is running, cleared when it stops or panics.
+    running: AtomicBool,
 }
 
 /// Configuration settings for the client.
-#[derive(Debug)]
 pub struct Config {
-    /// Datadog apm service name
+    /// Sends the traces to the datadog agent, defaults to `true`.
+    /// When `false` the client does not spawn its buffer consumer task, and `send_trace` discards the traces.
+    pub enabled: bool,
+    /// Datadog apm service name, empty by default.
+    /// The client logs a warning when it is empty, the traces are hard to find in datadog without it.
     pub service: String,
     /// Datadog apm environment
     pub env: Option<String>,
+    /// Version of the service, sent as the `version` tag for deployment tracking.
+    pub version: Option<String>,
+    /// Name of the host running the service, sent as the `_dd.hostname` meta of the spans.
+    /// The agent attributes the traces to its own host without it, set it when the agent runs on another host.
+    pub hostname: Option<String>,
+    /// Tags added to every span, like `git.commit.sha` or `region`. Span tags with the same key take precedence.
+    pub global_tags: HashMap<String, String>,
     /// Datadog agent host/ip, defaults to `localhost`.
     pub host: String,
     /// Datadog agent port, defaults to `8196`.
     pub port: String,
+    /// Datadog agent url, like `http://dd-agent.internal:8126` or `unix:///var/run/datadog/apm.socket`.
+    /// When set it takes precedence over `host` and `port`. `https://` urls require the `tls` feature.
+    pub agent_url: Option<String>,
+    /// Datadog agent unix domain socket, like `/var/run/datadog/apm.socket`. When set it takes precedence over `agent_url`, `host` and `port`.
+    pub socket_path: Option<PathBuf>,
+    /// Other datadog agents to fail over to when the current one is unreachable, like `http://dd-agent-2.internal:8126`.
+    /// They are tried in order, after the agent of `host` and `port` or of `agent_url`. Defaults to none.
+    pub fallback_agent_urls: Vec<String>,
+    /// Full url of the traces endpoint, like `http://agent.internal/dd-apm/traces` for an agent behind a reverse proxy.
+    /// When set it is used verbatim and takes precedence over `socket_path`, `agent_url`, `host` and `port`:
+    /// the api version is not downgraded when the endpoint rejects it, and `fallback_agent_urls` are not used.
+    pub endpoint_url: Option<String>,
     /// Client buffer queue capacity, defaults to `std::u16::MAX`.
     /// It is used for limit the amount of traces being queued in memory before drop. The client should handle send all the traces before the queue is full, you usually don't need to change this value.
-    pub buffer_queue_capacity: u16,
+    pub buffer_queue_capacity: usize,
     /// The buffer size, defaults to 200. It's the amount of traces send in a single request to datadog agent.
-    pub buffer_size: u16,
+    pub buffer_size: usize,
+    /// Estimated size in bytes above which the buffer is sent before reaching `buffer_size`, defaults to 8 MiB.
+    /// It keeps the requests under the body size limit of the datadog agent when the traces have many spans.
+    pub max_payload_bytes: usize,
     /// The buffer flush maximum interval, defaults to 200 ms. It's the maximum amount of time between buffer flushes that is the time we wait to buffer the traces before send if the buffer does not reach the buffer_size.
     pub buffer_flush_max_interval: Duration,
+    /// Sends the buffer as soon as it receives a trace with an error span, defaults to `false`.
+    pub flush_on_error: bool,
+    /// Maximum amount of retries to send a batch of traces, defaults to 3.
+    /// Only transport errors, `5xx` and `429 Too Many Requests` responses from the datadog agent are retried.
+    /// A `429` response is retried after the whole delay of its `Retry-After` header, which is only limited by the amount of retries.
+    pub max_retries: u32,
+    /// Time to wait before the first retry, defaults to 100 ms. It doubles on every following retry.
+    pub retry_backoff: Duration,
+    /// Maximum amount of time spent waiting between the retries of a batch, defaults to 1 s.
+    /// A retry that would wait past this limit is not done, and the batch is dropped.
+    /// The `Retry-After` delays of the `429` responses do not count against this limit.
+    pub retry_backoff_limit: Duration,
+    /// Maximum amount of time to wait for the datadog agent to respond a request, defaults to 1 s.
+    /// A request that times out is retried like a transport error.
+    pub request_timeout: Duration,
+    /// Version of the agent traces api to use first, defaults to `ApiVersion::V05`.
+    /// The client downgrades to the older versions when the datadog agent does not support it.
+    pub api_version: ApiVersion,
+    /// Compression of the payloads sent to the datadog agent, defaults to `Compression::None`.
+    /// Small payloads do not benefit from it.
+    pub compression: Compression,
+    /// Validates every trace in `send_trace` with [`Trace::validate`](struct.Trace.html#method.validate), defaults to `false`.
+    /// An invalid trace is not sent, and `send_trace` returns the validation error.
+    pub validate_traces: bool,
+    /// Rate of the traces to send, between `0.0` and `1.0`, defaults to `1.0`.
+    /// `send_trace` discards the other traces, deciding from the trace id so the same traces are kept by every client
+    /// with the same rate. The rate is sent in the `_dd.agent_psr` metric of the root spans, and the kept traces are sent
+    /// with the `AutoKeep` priority unless their priority is `UserKeep` or `UserReject`.
+    pub sample_rate: f64,
+    /// Rate of the spans kept for App Analytics, clamped between `0.0` and `1.0`, sent in the `_dd1.sr.eausr` metric
+    /// of every span. Defaults to `None`, analytics disabled. [`Span::analytics_rate`](struct.Span.html#structfield.analytics_rate)
+    /// takes precedence for a single span.
+    pub analytics_sample_rate: Option<f64>,
+    /// Replaces the string and numeric literals of the `SqlInfo` queries with `?` before sending them, defaults to `false`.
+    /// A list of literals, like the values of an `IN` list, is replaced with a single `?`.
+    pub obfuscate_sql: bool,
+    /// Maximum length in bytes of the span meta values, like the tags and the error stack, defaults to 25000.
+    /// Longer values are truncated and end with `...[truncated]`, so the datadog agent does not reject the batch.
+    pub max_tag_value_len: usize,
+    /// Callback invoked with every dropped trace, defaults to `None`.
+    /// A trace is dropped when the buffer queue is full or when sending it to the datadog agent fails.
+    /// It is called from `send_trace` and from the buffer consumer task, so it must be cheap and not block.
+    pub on_drop: Option<DropCallback>,
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("enabled", &self.enabled)
+            .field("service", &self.service)
+            .field("env", &self.env)
+            .field("version", &self.version)
+            .field("hostname", &self.hostname)
+            .field("global_tags", &self.global_tags)
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("agent_url", &self.agent_url)
+            .field("socket_path", &self.socket_path)
+            .field("fallback_agent_urls", &self.fallback_agent_urls)
+            .field("endpoint_url", &self.endpoint_url)
+            .field("buffer_queue_capacity", &self.buffer_queue_capacity)
+            .field("buffer_size", &self.buffer_size)
+            .field("max_payload_bytes", &self.max_payload_bytes)
+            .field("buffer_flush_max_interval", &self.buffer_flush_max_interval)
+            .field("flush_on_error", &self.flush_on_error)
+            .field("max_retries", &self.max_retries)
+            .field("retry_backoff", &self.retry_backoff)
+            .field("retry_backoff_limit", &self.retry_backoff_limit)
+            .field("request_timeout", &self.request_timeout)
+            .field("api_version", &self.api_version)
+            .field("compression", &self.compression)
+            .field("validate_traces", &self.validate_traces)
+            .field("sample_rate", &self.sample_rate)
+            .field("analytics_sample_rate", &self.analytics_sample_rate)
+            .field("obfuscate_sql", &self.obfuscate_sql)
+            .field("max_tag_value_len", &self.max_tag_value_len)
+            .field("on_drop", &self.on_drop.as_ref().map(|_| "Fn(&Trace)"))
+            .finish()
+    }
 }
 
 impl Default for Config {
     fn default() -> Self {
         Config {
+            enabled: true,
             env: None,
+            version: None,
+            hostname: None,
+            global_tags: HashMap::new(),
             host: "localhost".to_string(),
             port: "8126".to_string(),
+            agent_url: None,
+            socket_path: None,
+            fallback_agent_urls: Vec::new(),
+            endpoint_url: None,
             service: "".to_string(),
-            buffer_queue_capacity: std::u16::MAX,
+            buffer_queue_capacity: u16::MAX as usize,
             buffer_size: 200,
+            max_payload_bytes: 8 * 1024 * 1024,
             buffer_flush_max_interval: Duration::from_millis(200),
+            flush_on_error: false,
+            max_retries: 3,
+            retry_backoff: Duration::from_millis(100),
+            retry_backoff_limit: Duration::from_secs(1),
+            request_timeout: Duration::from_secs(1),
+            api_version: ApiVersion::V05,
+            compression: Compression::None,
+            validate_traces: false,
+            sample_rate: 1.0,
+            analytics_sample_rate: None,
+            obfuscate_sql: false,
+            max_tag_value_len: 25_000,
+            on_drop: None,
+        }
+    }
+}
+
+impl Config {
+    /// Creates the config from the standard datadog environment variables, using the defaults for the unset ones:
+    /// - `DD_AGENT_HOST` for `host`;
+    /// - `DD_TRACE_AGENT_PORT` for `port`;
+    /// - `DD_ENV` for `env`;
+    /// - `DD_SERVICE` for `service`;
+    /// - `DD_VERSION` for `version`;
+    /// - `DD_TAGS` for `global_tags`, as a list of `key:value` pairs separated by commas or spaces;
+    /// - `DD_TRACE_SAMPLE_RATE` for `sample_rate`, ignored when it is not a number between `0.0` and `1.0`.
+    pub fn from_env() -> Config {
+        let default = Config::default();
+        Config {
+            host: env_var("DD_AGENT_HOST").unwrap_or(default.host),
+            port: env_var("DD_TRACE_AGENT_PORT").unwrap_or(default.port),
+            env: env_var("DD_ENV").or(default.env),
+            service: env_var("DD_SERVICE").unwrap_or(default.service),
+            version: env_var("DD_VERSION").or(default.version),
+            global_tags: env_var("DD_TAGS")
+                .map(|tags| parse_tags(&tags))
+                .unwrap_or(default.global_tags),
+            sample_rate: env_var("DD_TRACE_SAMPLE_RATE")
+                .and_then(|rate| rate.parse().ok())
+                .filter(|rate| (0.0..=1.0).contains(rate))
+                .unwrap_or(default.sample_rate),
+            ..default
         }
     }
 }
 
+/// Reads an environment variable, treating an empty value as unset.
+fn env_var(key: &str) -> Option<String> {
+    std::env::var(key).ok().filter(|value| !value.is_empty())
+}
+
+/// Parses `key:value` pairs separated by commas or spaces, skipping the ones without a value.
+fn parse_tags(tags: &str) -> HashMap<String, String> {
+    tags.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|tag| {
+            let mut parts = tag.splitn(2, ':');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if !key.is_empty() && !value.is_empty() => {
+                    Some((key.to_string(), value.to_string()))
+                }
+                _ => None,
+            }
+        })
+        .collect()
+}
+
 impl Client {
+    /// Creates the client and spawns its buffer consumer task.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the config is invalid, use [`Client::try_new`](#method.try_new) to handle the error instead.
     pub fn new(config: Config) -> Client {
-        let (buffer_sender, buffer_receiver) = mpsc::channel(config.buffer_queue_capacity as usize);
+        match Client::try_new(config) {
+            Ok(client) => client,
+            Err(err) => panic!("{}", err),
+        }
+    }
+
+    /// Creates a client that is not `enabled`, discarding the traces without spawning its buffer consumer task.
+    pub fn disabled() -> Client {
+        Client::new(Config {
+            enabled: false,
+            ..Default::default()
+        })
+    }
+
+    /// Creates the client and spawns its buffer consumer task, failing if the config is invalid.
+    pub fn try_new(config: Config) -> Result<Client, ConfigError> {
+        let agent_address = AgentAddress::from_config(&config)?;
+        let connector = agent_address.connector()?;
+        Client::build(config, agent_address, connector, Arc::new(SystemClock))
+    }
+
+    /// Creates the client connecting to the datadog agent with `connector`, like a tls or proxy connector,
+    /// and spawns its buffer consumer task.
+    ///
+    /// The `agent_url` and `fallback_agent_urls` may be `https://` urls, the connector must support them.
+    /// With the `tls` feature [`Client::new`](#method.new) already supports them.
+    /// Fails with `ConfigError::UnixSocketConnector` when `socket_path` or a `unix://` agent url is set.
+    pub fn with_connector<C>(config: Config, connector: C) -> Result<Client, ConfigError>
+    where
+        C: Service<Uri> + Clone + Send + Sync + 'static,
+        C::Response: AsyncRead + AsyncWrite + Connection + Send + Unpin + 'static,
+        C::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        C::Future: Send + 'static,
+    {
+        Client::with_connector_and_clock(config, connector, Arc::new(SystemClock))
+    }
+
+    /// Like [`Client::with_connector`](#method.with_connector), with the flush interval timed by `clock`.
+    pub(crate) fn with_connector_and_clock<C>(
+        config: Config,
+        connector: C,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Client, ConfigError>
+    where
+        C: Service<Uri> + Clone + Send + Sync + 'static,
+        C::Response: AsyncRead + AsyncWrite + Connection + Send + Unpin + 'static,
+        C::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
+        C::Future: Send + 'static,
+    {
+        let agent_address = AgentAddress::from_config(&config)?;
+        if let AgentAddress::UnixSocket(_) = agent_address {
+            return Err(ConfigError::UnixSocketConnector);
+        }
+        let connector = AgentConnector::Custom(CustomConnector::new(connector));
+        Client::build(config, agent_address, connector, clock)
+    }
+
+    fn build(
+        config: Config,
+        agent_address: AgentAddress,
+        connector: AgentConnector,
+        clock: Arc<dyn Clock>,
+    ) -> Result<Client, ConfigError> {
+        if !(0.0..=1.0).contains(&config.sample_rate) {
+            return Err(ConfigError::InvalidSampleRate(config.sample_rate));
+        }
+        if config.enabled && config.service.trim().is_empty() {
+            warn!("the datadog apm service name is empty, set `Config::service` or the DD_SERVICE environment variable");
+        }
+        let endpoint = agent_address.traces_endpoint(config.api_version);
+        let agent_base_urls = agent_address.with_fallbacks(&config.fallback_agent_urls)?;
+        let https_url = agent_base_urls.iter().find(|url| url.starts_with("https:"));
+        let connector = match (connector, https_url) {
+            #[cfg(feature = "tls")]
+            (AgentConnector::Tcp(_), Some(_)) => {
+                AgentConnector::Custom(CustomConnector::new(hyper_rustls::HttpsConnector::new()))
+            }
+            // the default connector only supports plain http without the `tls` feature
+            #[cfg(not(feature = "tls"))]
+            (AgentConnector::Tcp(_), Some(https_url)) => {
+                return Err(ConfigError::InvalidAgentUrl(https_url.clone()))
+            }
+            (connector, _) => connector,
+        };
+        let (buffer_sender, buffer_receiver) = mpsc::channel(config.buffer_queue_capacity);
+        let (control_sender, control_receiver) = mpsc::unbounded_channel();
 
         let client = Client {
+            enabled: config.enabled,
             env: config.env,
+            version: config.version,
+            hostname: config.hostname,
             service: config.service,
-            endpoint: format!("http://{}:{}/v0.3/traces", config.host, config.port),
-            http_client: hyper::Client::new(),
-            buffer_sender: buffer_sender,
-            buffer_size: config.buffer_size as usize,
+            global_tags: config.global_tags,
+            endpoint: Arc::new(Mutex::new(endpoint)),
+            agent_base_urls: Arc::new(agent_base_urls),
+            http_client: hyper::Client::builder().build(connector),
+            buffer_sender,
+            control_sender,
+            consumer_task: Arc::new(Mutex::new(None)),
+            buffer_queue_capacity: config.buffer_queue_capacity,
+            buffer_size: config.buffer_size,
+            max_payload_bytes: config.max_payload_bytes,
             buffer_flush_max_interval: config.buffer_flush_max_interval,
+            flush_on_error: config.flush_on_error,
+            max_retries: config.max_retries,
+            retry_backoff: config.retry_backoff,
+            retry_backoff_limit: config.retry_backoff_limit,
+            request_timeout: config.request_timeout,
+            compression: config.compression,
+            validate_traces: config.validate_traces,
+            sample_rate: config.sample_rate,
+            analytics_sample_rate: config.analytics_sample_rate,
+            obfuscate_sql: config.obfuscate_sql,
+            max_tag_value_len: config.max_tag_value_len,
+            container_id: container_id(),
+            sampling_rates: Arc::new(Mutex::new(HashMap::new())),
+            on_drop: config.on_drop,
+            stats: Arc::new(Stats::default()),
+            clock,
         };
 
-        spawn_consume_buffer_task(buffer_receiver, client.clone());
+        if client.enabled {
+            let consumer_task =
+                spawn_consume_buffer_task(buffer_receiver, control_receiver, client.clone());
+            client.stats.running.store(true, Ordering::Relaxed);
+            // watches the consumer task, so a panic is logged when it happens instead of at the shutdown
+            let stats = client.stats.clone();
+            let watcher_task = tokio::spawn(async move {
+                if let Err(err) = consumer_task.await {
+                    error!(
+                        "buffer consumer task failed, the traces are no longer sent: {:?}",
+                        err
+                    );
+                }
+                stats.running.store(false, Ordering::Relaxed);
+            });
+            *client.consumer_task.lock().unwrap() = Some(watcher_task);
+        }
 
-        client
+        Ok(client)
     }
 
-    pub fn send_trace(mut self, trace: Trace) {
-        match self.buffer_sender.try_send(trace) {
-            Ok(_) => trace!("trace enqueued"),
-            Err(err) => warn!("could not enqueue trace: {:?}", err),
-        };
+    /// Enqueues the trace to be sent, without blocking the caller.
+    ///
+    /// Does nothing when the client is not `enabled`.
+    ///
+    /// Fails with `TraceError::QueueFull` when the buffer queue is at capacity,
+    /// handing the trace back so the caller can decide what to do with it.
+    /// With `Config::validate_traces` it also fails with the error of an invalid trace.
+    pub fn send_trace(&self, mut trace: Trace) -> Result<(), TraceError> {
+        if !self.accept_trace(&mut trace)? {
+            return Ok(());
+        }
+
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        let result = self.buffer_sender.clone().try_send(trace);
+        if result.is_err() {
+            self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+        }
+        match result {
+            Ok(_) => {
+                self.stats.enqueued.fetch_add(1, Ordering::Relaxed);
+                trace!("trace enqueued");
+                Ok(())
+            }
+            Err(TrySendError::Full(trace)) => {
+                warn!("could not enqueue trace: buffer queue is full");
+                self.drop_traces(std::slice::from_ref(&trace));
+                Err(TraceError::QueueFull(trace))
+            }
+            Err(TrySendError::Closed(trace)) => {
+                warn!("could not enqueue trace: buffer consumer task is not running");
+                self.drop_traces(std::slice::from_ref(&trace));
+                Err(TraceError::Disconnected)
+            }
+        }
+    }
+
+    /// Enqueues the traces to be sent, like calling [`send_trace`](#method.send_trace) for each of them.
+    ///
+    /// Returns the number of traces accepted, the others are dropped: they are invalid,
+    /// or did not fit in the buffer queue and were handed to `Config::on_drop`.
+    pub fn send_traces(&self, traces: Vec<Trace>) -> usize {
+        traces
+            .into_iter()
+            .map(|trace| self.send_trace(trace))
+            .filter(Result::is_ok)
+            .count()
+    }
+
+    /// Enqueues the trace to be sent, waiting for room in the buffer queue when it is at capacity.
+    ///
+    /// Does nothing when the client is not `enabled`.
+    ///
+    /// Unlike [`send_trace`](#method.send_trace) it never drops a trace because of a full queue, applying
+    /// backpressure to the caller instead. It fails with `TraceError::Disconnected` after a shutdown,
+    /// and with `Config::validate_traces` with the error of an invalid trace.
+    pub async fn send_trace_async(&self, mut trace: Trace) -> Result<(), TraceError> {
+        if !self.accept_trace(&mut trace)? {
+            return Ok(());
+        }
+
+        self.stats.queued.fetch_add(1, Ordering::Relaxed);
+        match self.buffer_sender.clone().send(trace).await {
+            Ok(_) => {
+                self.stats.enqueued.fetch_add(1, Ordering::Relaxed);
+                trace!("trace enqueued");
+                Ok(())
+            }
+            Err(SendError(trace)) => {
+                self.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                warn!("could not enqueue trace: buffer consumer task is not running");
+                self.drop_traces(std::slice::from_ref(&trace));
+                Err(TraceError::Disconnected)
+            }
+        }
+    }
+
+    /// Validates and samples the trace before it is enqueued, returning `false` when it should not be sent.
+    fn accept_trace(&self, trace: &mut Trace) -> Result<bool, TraceError> {
+        if !self.enabled {
+            return Ok(false);
+        }
+        if self.validate_traces {
+            if let Err(err) = trace.validate() {
+                warn!("invalid trace {}: {}", trace.id, err);
+                return Err(err);
+            }
+        }
+        if self.sample_rate < 1.0 {
+            if !is_sampled(trace.id as u64, self.sample_rate) {
+                trace!("trace {} sampled out", trace.id);
+                return Ok(false);
+            }
+            // the sampler kept the trace, unless the application decided itself
+            if !matches!(trace.priority, Priority::UserKeep | Priority::UserReject) {
+                trace.priority = Priority::AutoKeep;
+            }
+            for span in trace
+                .spans
+                .iter_mut()
+                .filter(|span| span.parent_id.is_none())
+            {
+                span.metrics
+                    .insert("_dd.agent_psr".to_string(), self.sample_rate);
+            }
+        }
+        Ok(true)
+    }
+
+    /// Stops accepting new traces and sends everything that is buffered,
+    /// resolving once the requests to the datadog agent complete.
+    ///
+    /// After the shutdown the client can no longer send traces: `send_trace` fails with
+    /// `TraceError::Disconnected` on this client and on all of its clones.
+    pub async fn shutdown(self) {
+        let (done_sender, done_receiver) = oneshot::channel();
+        if self
+            .control_sender
+            .send(Control::Shutdown(done_sender))
+            .is_ok()
+        {
+            let _ = done_receiver.await;
+        }
+
+        let consumer_task = self.consumer_task.lock().unwrap().take();
+        if let Some(consumer_task) = consumer_task {
+            if let Err(err) = consumer_task.await {
+                error!("buffer consumer task failed: {:?}", err);
+            }
+        }
+    }
+
+    /// Sends all the traces enqueued so far without waiting for `buffer_flush_max_interval`,
+    /// resolving once the requests to the datadog agent complete.
+    ///
+    /// Does nothing when there are no traces to send.
+    pub async fn flush(&self) {
+        let (done_sender, done_receiver) = oneshot::channel();
+        if self
+            .control_sender
+            .send(Control::Flush(done_sender))
+            .is_ok()
+        {
+            let _ = done_receiver.await;
+        }
+    }
+
+    /// Returns the counters of the traces handled so far by this client and all of its clones.
+    pub fn stats(&self) -> ClientStats {
+        ClientStats {
+            enqueued: self.stats.enqueued.load(Ordering::Relaxed),
+            sent: self.stats.sent.load(Ordering::Relaxed),
+            dropped: self.stats.dropped.load(Ordering::Relaxed),
+            send_errors: self.stats.send_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// The clock timing the client, and the spans of the integrations sending through it.
+    #[cfg(any(feature = "actix", feature = "tracing"))]
+    pub(crate) fn clock(&self) -> &dyn Clock {
+        &*self.clock
+    }
+
+    /// Returns whether the buffer consumer task is running and sending the traces.
+    ///
+    /// It is `false` for a disabled client, after a shutdown, and when the task panicked,
+    /// for instance in the `on_drop` callback. A supervisor can poll it to alert or to create a new client.
+    pub fn is_running(&self) -> bool {
+        self.stats.running.load(Ordering::Relaxed)
     }
 
-    async fn send_traces(self, traces: Vec<Trace>) {
-        let traces = traces
+    /// Encodes the trace to the msgpack payload sent to the datadog agent, without sending it.
+    ///
+    /// The payload uses the api version the client currently sends to, see `Config::api_version`,
+    /// and is the same for the same trace, so it can be compared to a golden file.
+    pub fn encode_trace(&self, trace: &Trace) -> Result<Vec<u8>, TraceError> {
+        self.encode_traces(std::slice::from_ref(trace))
+    }
+
+    /// Encodes the traces to the msgpack payload of a single request to the datadog agent, without sending it.
+    /// Like the sent payloads, it does not include the traces without spans.
+    ///
+    /// Decoding the `v0.4` payload to json:
+    /// ```
+    /// use datadog_apm::{ApiVersion, Client, Config, Span, Trace};
+    ///
+    /// # tokio::runtime::Runtime::new().unwrap().block_on(async {
+    /// let client = Client::new(Config {
+    ///     service: "my-crate".to_string(),
+    ///     api_version: ApiVersion::V04,
+    ///     ..Default::default()
+    /// });
+    /// let mut trace = Trace::new(Default::default());
+    /// trace.spans.push(Span::builder("request", "GET /path").tag("user.id", "1").build());
+    ///
+    /// let payload = client.encode_traces(&[trace]).unwrap();
+    /// let json: serde_json::Value = rmp_serde::from_read_ref(&payload).unwrap();
+    /// assert_eq!(json[0][0]["service"], "my-crate");
+    /// assert_eq!(json[0][0]["meta"]["user.id"], "1");
+    /// # });
+    /// ```
+    pub fn encode_traces(&self, traces: &[Trace]) -> Result<Vec<u8>, TraceError> {
+        let mut raw_traces = self.map_to_raw_traces(traces);
+        raw_traces.retain(|raw_spans| !raw_spans.is_empty());
+        let version = self.endpoint.lock().unwrap().version;
+        version
+            .serialize(&raw_traces)
+            .map_err(|err| TraceError::Encode(err.to_string()))
+    }
+
+    fn map_to_raw_traces(&self, traces: &[Trace]) -> Vec<Vec<RawSpan>> {
+        traces
             .iter()
-            .map(|trace| map_to_raw_spans(trace, self.env.clone(), self.service.clone()))
-            .collect::<Vec<Vec<RawSpan>>>();
+            .map(|trace| {
+                let mut raw_spans = map_to_raw_spans(
+                    trace,
+                    self.env.as_deref(),
+                    self.version.as_deref(),
+                    &self.service,
+                    &self.global_tags,
+                    self.obfuscate_sql,
+                );
+                for raw_span in &mut raw_spans {
+                    if let Some(rate) = self.analytics_sample_rate {
+                        raw_span
+                            .metrics
+                            .entry("_dd1.sr.eausr".to_string())
+                            .or_insert_with(|| rate.clamp(0.0, 1.0));
+                    }
+                    if let Some(hostname) = &self.hostname {
+                        raw_span
+                            .meta
+                            .insert("_dd.hostname".to_string(), hostname.clone());
+                    }
+                    for value in raw_span.meta.values_mut() {
+                        truncate_tag_value(value, self.max_tag_value_len);
+                    }
+                }
+                raw_spans
+            })
+            .collect()
+    }
+
+    /// Returns the sampling rates the datadog agent returned in its last response, by service and environment.
+    ///
+    /// The keys are like `service:my-crate,env:production`, and `service:,env:` for the default rate.
+    /// The map is empty until the agent answers with rates, older agent api versions do not.
+    pub fn sampling_rates(&self) -> HashMap<String, f64> {
+        self.sampling_rates.lock().unwrap().clone()
+    }
 
-        let trace_count = traces.len();
-        let payload = serialize_as_msgpack(traces);
+    /// Returns the amount of traces waiting in the buffer queue to be picked up by the buffer consumer task.
+    pub fn queue_len(&self) -> usize {
+        self.stats.queued.load(Ordering::Relaxed)
+    }
+
+    /// Returns the buffer queue capacity, the configured `buffer_queue_capacity`.
+    pub fn queue_capacity(&self) -> usize {
+        self.buffer_queue_capacity
+    }
+
+    /// Returns the amount of traces dropped so far, the same as `stats().dropped`.
+    pub fn dropped_traces(&self) -> u64 {
+        self.stats.dropped.load(Ordering::Relaxed)
+    }
+
+    fn drop_traces(&self, traces: &[Trace]) {
+        self.stats
+            .dropped
+            .fetch_add(traces.len() as u64, Ordering::Relaxed);
+        if let Some(on_drop) = &self.on_drop {
+            traces.iter().for_each(|trace| on_drop(trace));
+        }
+    }
+
+    async fn send_batch(self, mut traces: Vec<Trace>) {
+        // the agent counts a trace without spans in X-Datadog-Trace-Count, but has nothing to process
+        traces.retain(|trace| !trace.spans.is_empty());
+        if traces.is_empty() {
+            return;
+        }
+
+        let raw_traces = self.map_to_raw_traces(&traces);
+        let trace_count = raw_traces.len();
+        let mut payload: Option<(ApiVersion, Bytes)> = None;
+
+        let mut retries = 0;
+        let mut failovers = 0;
+        let mut backoff = self.retry_backoff;
+        let mut total_backoff = Duration::from_secs(0);
+        loop {
+            let endpoint = self.endpoint.lock().unwrap().clone();
+            let body = match &payload {
+                Some((version, body)) if *version == endpoint.version => body.clone(),
+                _ => match endpoint.version.serialize(&raw_traces) {
+                    Ok(body) => match self.compression.compress(body) {
+                        Ok(body) => {
+                            let body = Bytes::from(body);
+                            payload = Some((endpoint.version, body.clone()));
+                            body
+                        }
+                        Err(err) => {
+                            error!("error compressing traces: {}", err);
+                            self.drop_traces(&traces);
+                            return;
+                        }
+                    },
+                    Err(err) => {
+                        error!("error serializing traces: {}", err);
+                        self.drop_traces(&traces);
+                        return;
+                    }
+                },
+            };
+
+            match self.do_send_batch(&endpoint, body, trace_count).await {
+                SendOutcome::Sent => {
+                    self.stats
+                        .sent
+                        .fetch_add(trace_count as u64, Ordering::Relaxed);
+                    trace!("{} traces sent to datadog", trace_count);
+                    return;
+                }
+                SendOutcome::Downgrade if self.downgrade_endpoint(&endpoint) => {}
+                SendOutcome::Failover if failovers + 1 < self.agent_base_urls.len() => {
+                    self.failover_endpoint(&endpoint);
+                    failovers += 1;
+                }
+                SendOutcome::RateLimited(Some(retry_after)) if retries < self.max_retries => {
+                    self.clock.sleep(retry_after).await;
+                    retries += 1;
+                }
+                SendOutcome::Retry | SendOutcome::Failover | SendOutcome::RateLimited(None)
+                    if retries < self.max_retries
+                        && total_backoff + backoff <= self.retry_backoff_limit =>
+                {
+                    self.clock.sleep(backoff).await;
+                    retries += 1;
+                    total_backoff += backoff;
+                    backoff *= 2;
+                }
+                _ => {
+                    self.drop_traces(&traces);
+                    return;
+                }
+            }
+        }
+    }
+
+    /// Downgrades the endpoint to an older api version, returning false when there is none.
+    fn downgrade_endpoint(&self, rejected: &Endpoint) -> bool {
+        let mut endpoint = self.endpoint.lock().unwrap();
+        if endpoint.verbatim {
+            return false;
+        }
+        if endpoint.version != rejected.version {
+            return true;
+        }
+        match endpoint.version.downgrade() {
+            Some(version) => {
+                warn!(
+                    "datadog agent does not support {}, downgrading to {}",
+                    endpoint.version.path(),
+                    version.path()
+                );
+                endpoint.version = version;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Switches the endpoint to the next agent, unless another batch already did.
+    fn failover_endpoint(&self, unreachable: &Endpoint) {
+        let mut endpoint = self.endpoint.lock().unwrap();
+        if endpoint.base_url != unreachable.base_url {
+            return;
+        }
+        let index = self
+            .agent_base_urls
+            .iter()
+            .position(|base_url| *base_url == endpoint.base_url)
+            .unwrap_or(0);
+        let next = &self.agent_base_urls[(index + 1) % self.agent_base_urls.len()];
+        warn!(
+            "datadog agent {} is unreachable, failing over to {}",
+            endpoint.base_url, next
+        );
+        endpoint.base_url = next.clone();
+    }
 
-        let req = Request::builder()
+    fn build_request(
+        &self,
+        endpoint: &Endpoint,
+        payload: Bytes,
+        trace_count: usize,
+    ) -> Result<Request<Body>, hyper::http::Error> {
+        let mut request = Request::builder()
             .method(Method::POST)
-            .uri(self.endpoint)
+            .uri(endpoint.url())
             .header("content-type", "application/msgpack")
             .header("content-length", payload.len())
-            .header("X-Datadog-Trace-Count", trace_count)
-            .body(Body::from(payload))
-            .unwrap();
+            .header("X-Datadog-Trace-Count", trace_count);
+        if let Some(encoding) = self.compression.content_encoding() {
+            request = request.header("content-encoding", encoding);
+        }
+        if let Some(container_id) = &self.container_id {
+            request = request.header("Datadog-Container-ID", container_id.as_str());
+        }
+        request.body(Body::from(payload))
+    }
+
+    /// Waits for `future` at most `duration` on the client clock, returning `None` when it times out.
+    async fn timeout<F: Future>(&self, duration: Duration, future: F) -> Option<F::Output> {
+        tokio::select! {
+            output = future => Some(output),
+            _ = self.clock.sleep(duration) => None,
+        }
+    }
+
+    /// Reads the sampling rates from the body of a successful response, keeping the previous ones when it has none.
+    async fn update_sampling_rates(&self, body: Body) {
+        let body = match self
+            .timeout(self.request_timeout, hyper::body::to_bytes(body))
+            .await
+        {
+            Some(Ok(body)) => body,
+            _ => return,
+        };
+        match serde_json::from_slice::<AgentResponse>(&body) {
+            Ok(AgentResponse {
+                rate_by_service: Some(rates),
+            }) => *self.sampling_rates.lock().unwrap() = rates,
+            Ok(_) => {}
+            Err(err) => debug!("could not parse the datadog agent response: {}", err),
+        }
+    }
 
-        match self.http_client.request(req).await {
+    async fn do_send_batch(
+        &self,
+        endpoint: &Endpoint,
+        payload: Bytes,
+        trace_count: usize,
+    ) -> SendOutcome {
+        let req = match self.build_request(endpoint, payload, trace_count) {
+            Ok(req) => req,
+            Err(err) => {
+                error!("error building the request to datadog: {}", err);
+                return SendOutcome::Fail;
+            }
+        };
+        let response = self.timeout(self.request_timeout, self.http_client.request(req));
+        let response = match response.await {
+            Some(response) => response,
+            None => {
+                self.stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                error!(
+                    "error sending traces to datadog: no response after {:?}",
+                    self.request_timeout
+                );
+                return SendOutcome::Retry;
+            }
+        };
+        match response {
+            Ok(resp) if resp.status().is_success() => {
+                self.update_sampling_rates(resp.into_body()).await;
+                SendOutcome::Sent
+            }
+            Ok(resp) if Endpoint::should_downgrade(resp.status()) => SendOutcome::Downgrade,
+            Ok(resp) if resp.status() == StatusCode::TOO_MANY_REQUESTS => {
+                self.stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                warn!("datadog agent is rate limiting the traces requests");
+                SendOutcome::RateLimited(retry_after(&resp))
+            }
             Ok(resp) => {
-                if resp.status().is_success() {
-                    trace!("{} traces sent to datadog", trace_count)
+                self.stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                error!("error sending traces to datadog: {:?}", resp);
+                if resp.status().is_server_error() {
+                    SendOutcome::Retry
+                } else {
+                    SendOutcome::Fail
+                }
+            }
+            Err(err) => {
+                self.stats.send_errors.fetch_add(1, Ordering::Relaxed);
+                error!("error sending traces to datadog: {:?}", err);
+                if err.is_connect() {
+                    SendOutcome::Failover
                 } else {
-                    error!("error sending traces to datadog: {:?}", resp)
+                    SendOutcome::Retry
                 }
             }
-            Err(err) => error!("error sending traces to datadog: {:?}", err),
         }
     }
 }
 
+/// Body of the datadog agent response to the traces requests.
+#[derive(Debug, Deserialize)]
+struct AgentResponse {
+    rate_by_service: Option<HashMap<String, f64>>,
+}
+
+/// Outcome of a single attempt to send a batch of traces to the datadog agent.
+#[derive(Debug, PartialEq)]
+enum SendOutcome {
+    Sent,
+    Downgrade,
+    /// The agent is unreachable, the next fallback agent should be tried.
+    Failover,
+    Retry,
+    /// The agent answered `429`, with the delay of its `Retry-After` header when it has one.
+    RateLimited(Option<Duration>),
+    Fail,
+}
+
+/// Reads the delay in seconds of the `Retry-After` header, the http date format is not supported.
+fn retry_after(response: &Response<Body>) -> Option<Duration> {
+    let seconds = response
+        .headers()
+        .get(RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(Duration::from_secs(seconds))
+}
+
 #[derive(Debug, Clone)]
 pub struct Trace {
-    pub id: u64,
+    /// Trace id, up to 128 bits like the OpenTelemetry trace ids.
+    /// The high 64 bits are sent in the `_dd.p.tid` meta of the spans.
+    pub id: u128,
     pub spans: Vec<Span>,
-    pub priority: u32,
+    pub priority: Priority,
+    /// Origin of the trace, like `synthetics` or `rum`, sent in the `_dd.origin` meta of the spans.
+    pub origin: Option<String>,
+}
+
+/// Sampling priority of a trace, telling datadog whether to keep it.
+///
+/// The `Auto` priorities are the sampler decisions, the `User` ones are set by the application.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Priority {
+    UserReject,
+    AutoReject,
+    #[default]
+    AutoKeep,
+    UserKeep,
+}
+
+impl Priority {
+    /// Value sent in the `_sampling_priority_v1` metric.
+    pub fn value(self) -> i32 {
+        match self {
+            Priority::UserReject => -1,
+            Priority::AutoReject => 0,
+            Priority::AutoKeep => 1,
+            Priority::UserKeep => 2,
+        }
+    }
+}
+
+impl Trace {
+    /// Creates a trace without spans and with a random 63-bit id.
+    pub fn new(priority: Priority) -> Trace {
+        Trace {
+            id: u128::from(random_id()),
+            spans: Vec::new(),
+            priority,
+            origin: None,
+        }
+    }
+
+    /// Checks that the span ids are unique, and that every `parent_id` is the id of another span in the trace.
+    pub fn validate(&self) -> Result<(), TraceError> {
+        let mut ids = HashSet::with_capacity(self.spans.len());
+        for span in &self.spans {
+            if !ids.insert(span.id) {
+                return Err(TraceError::DuplicateSpanId(span.id));
+            }
+        }
+        for span in &self.spans {
+            match span.parent_id {
+                Some(parent_id) if parent_id == span.id || !ids.contains(&parent_id) => {
+                    return Err(TraceError::UnknownParentId {
+                        span_id: span.id,
+                        parent_id,
+                    });
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -128,8 +1098,91 @@ pub struct Span {
     pub error: Option<ErrorInfo>,
     pub http: Option<HttpInfo>,
     pub sql: Option<SqlInfo>,
-    pub r#type: String,
+    pub messaging: Option<MessagingInfo>,
+    pub cache: Option<CacheInfo>,
+    pub r#type: SpanType,
     pub tags: HashMap<String, String>,
+    /// Numeric values aggregated by datadog, like the rows returned by a query.
+    pub metrics: HashMap<String, f64>,
+    /// Overrides the client service for this span, like `my-api-postgres` for a database span.
+    pub service: Option<String>,
+    /// Computes the trace metrics (hits, errors and latency) of this span, sending the `_dd.measured` metric.
+    /// Datadog only computes them for the service entry spans otherwise.
+    pub measured: bool,
+    /// Rate of the span kept for App Analytics, clamped between `0.0` and `1.0`, sent in the `_dd1.sr.eausr` metric.
+    /// Opts a single span in, like a checkout request, without enabling analytics for the whole service.
+    pub analytics_rate: Option<f64>,
+    /// Spans of other traces this span is related to, like the messages processed by a batch job.
+    pub links: Vec<SpanLink>,
+}
+
+/// Reference from a span to a span of another trace.
+#[derive(Debug, Clone, Default)]
+pub struct SpanLink {
+    pub trace_id: u128,
+    pub span_id: u64,
+    pub attributes: HashMap<String, String>,
+}
+
+/// Type of a span, used by datadog to categorize it.
+///
+/// Strings are converted to the matching variant, and to `Custom` when there is none.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum SpanType {
+    Web,
+    Http,
+    Db,
+    Cache,
+    Worker,
+    Queue,
+    Template,
+    Custom(String),
+}
+
+impl SpanType {
+    /// The datadog type string, like `web` or `db`.
+    pub fn as_str(&self) -> &str {
+        match self {
+            SpanType::Web => "web",
+            SpanType::Http => "http",
+            SpanType::Db => "db",
+            SpanType::Cache => "cache",
+            SpanType::Worker => "worker",
+            SpanType::Queue => "queue",
+            SpanType::Template => "template",
+            SpanType::Custom(r#type) => r#type,
+        }
+    }
+}
+
+impl From<&str> for SpanType {
+    fn from(r#type: &str) -> Self {
+        match r#type {
+            "web" => SpanType::Web,
+            "http" => SpanType::Http,
+            "db" => SpanType::Db,
+            "cache" => SpanType::Cache,
+            "worker" => SpanType::Worker,
+            "queue" => SpanType::Queue,
+            "template" => SpanType::Template,
+            _ => SpanType::Custom(r#type.to_string()),
+        }
+    }
+}
+
+impl From<String> for SpanType {
+    fn from(r#type: String) -> Self {
+        match SpanType::from(r#type.as_str()) {
+            SpanType::Custom(_) => SpanType::Custom(r#type),
+            span_type => span_type,
+        }
+    }
+}
+
+impl fmt::Display for SpanType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -139,11 +1192,41 @@ pub struct ErrorInfo {
     pub stack: String,
 }
 
+impl ErrorInfo {
+    /// Fills `msg` with the error message, `type` with the error type name and `stack` with the messages
+    /// of its `source` chain, one per line.
+    ///
+    /// The type name is the one known at the call site, so a `Box<dyn Error>` is named `dyn Error`.
+    pub fn from_error<E: std::error::Error + ?Sized>(error: &E) -> ErrorInfo {
+        let mut stack = String::new();
+        let mut source = error.source();
+        while let Some(error) = source {
+            if !stack.is_empty() {
+                stack.push('\n');
+            }
+            stack.push_str(&format!("caused by: {}", error));
+            source = error.source();
+        }
+        ErrorInfo {
+            r#type: std::any::type_name::<E>().to_string(),
+            msg: error.to_string(),
+            stack,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct HttpInfo {
     pub url: String,
     pub status_code: String,
     pub method: String,
+    /// Size of the request body in bytes, sent in the `http.request.content_length` meta.
+    pub request_size: Option<u64>,
+    /// Size of the response body in bytes, sent in the `http.response.content_length` meta.
+    pub response_size: Option<u64>,
+    /// Route template matching the url, like `/users/:id`, sent in the `http.route` meta.
+    /// The span resource should be the route too, so that the endpoints are grouped by route instead of by url.
+    pub route: Option<String>,
 }
 
 #[derive(Debug, Clone)]
@@ -153,6 +1236,34 @@ pub struct SqlInfo {
     pub db: String,
 }
 
+/// Message queue operation of a span, like publishing to a kafka topic or receiving from a sqs queue.
+#[derive(Debug, Clone)]
+pub struct MessagingInfo {
+    /// The messaging system, like `kafka`, `rabbitmq` or `aws_sqs`.
+    pub system: String,
+    /// The topic or queue name.
+    pub destination: String,
+    /// The operation, like `publish`, `receive` or `process`.
+    pub operation: String,
+}
+
+/// Cache command of a span, like a redis `GET`.
+#[derive(Debug, Clone)]
+pub struct CacheInfo {
+    /// The cache system, like `redis` or `memcached`.
+    pub system: String,
+    /// The command, sent in the `redis.raw_command` meta for redis and in `cache.command` otherwise.
+    pub command: String,
+    /// The key, truncated to 100 bytes in the `cache.key` meta and not sent when empty.
+    pub key: String,
+}
+
+#[derive(Debug)]
+enum Control {
+    Flush(oneshot::Sender<()>),
+    Shutdown(oneshot::Sender<()>),
+}
+
 #[derive(Debug, Serialize, Clone, PartialEq)]
 struct RawSpan {
     service: String,
@@ -164,142 +1275,1896 @@ struct RawSpan {
     start: u64,
     duration: u64,
     error: i32,
-    meta: HashMap<String, String>,
-    metrics: HashMap<String, f64>,
+    // sorted, so the same trace is always serialized to the same payload
+    meta: BTreeMap<String, String>,
+    metrics: BTreeMap<String, f64>,
     r#type: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    span_links: Vec<RawSpanLink>,
 }
 
-fn spawn_consume_buffer_task(mut buffer_receiver: mpsc::Receiver<Trace>, client: Client) {
+#[derive(Debug, Serialize, Clone, PartialEq)]
+struct RawSpanLink {
+    trace_id: u64,
+    #[serde(skip_serializing_if = "is_zero")]
+    trace_id_high: u64,
+    span_id: u64,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    attributes: BTreeMap<String, String>,
+}
+
+fn is_zero(value: &u64) -> bool {
+    *value == 0
+}
+
+fn spawn_consume_buffer_task(
+    mut buffer_receiver: mpsc::Receiver<Trace>,
+    mut control_receiver: mpsc::UnboundedReceiver<Control>,
+    client: Client,
+) -> JoinHandle<()> {
     tokio::spawn(async move {
         let mut buffer = Vec::with_capacity(client.buffer_size);
-        let mut last_flushed_at = SystemTime::now();
+        // estimated payload size of the buffered traces
+        let mut buffer_bytes = 0;
+        let mut flush_tick = client.clock.sleep(client.buffer_flush_max_interval);
         loop {
-            let client = client.clone();
-
-            match buffer_receiver.try_recv() {
-                Ok(trace) => {
+            tokio::select! {
+                Some(trace) = buffer_receiver.recv() => {
+                    client.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                    let flush = client.flush_on_error && trace.spans.iter().any(|span| span.error.is_some());
+                    let trace_bytes = estimated_size(&trace);
+                    if !buffer.is_empty() && buffer_bytes + trace_bytes > client.max_payload_bytes {
+                        client.clone().send_batch(std::mem::take(&mut buffer)).await;
+                        buffer_bytes = 0;
+                    }
+                    buffer_bytes += trace_bytes;
                     buffer.push(trace);
+                    if flush || buffer.len() >= client.buffer_size || buffer_bytes >= client.max_payload_bytes {
+                        client.clone().send_batch(std::mem::take(&mut buffer)).await;
+                        buffer_bytes = 0;
+                    }
                 }
-                Err(_) => {
-                    tokio::time::delay_for(client.buffer_flush_max_interval).await;
+                _ = &mut flush_tick => {
+                    flush_tick = client.clock.sleep(client.buffer_flush_max_interval);
+                    if !buffer.is_empty() {
+                        client.clone().send_batch(std::mem::take(&mut buffer)).await;
+                        buffer_bytes = 0;
+                    }
+                }
+                Some(control) = control_receiver.recv() => {
+                    while let Ok(trace) = buffer_receiver.try_recv() {
+                        client.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                        buffer.push(trace);
+                    }
+                    match control {
+                        Control::Flush(done) => {
+                            send_in_batches(&client, std::mem::take(&mut buffer)).await;
+                            buffer_bytes = 0;
+                            let _ = done.send(());
+                        }
+                        Control::Shutdown(done) => {
+                            buffer_receiver.close();
+                            while let Ok(trace) = buffer_receiver.try_recv() {
+                                client.stats.queued.fetch_sub(1, Ordering::Relaxed);
+                                buffer.push(trace);
+                            }
+                            send_in_batches(&client, buffer).await;
+                            let _ = done.send(());
+                            return;
+                        }
+                    }
                 }
-            }
-
-            if buffer.len() == client.buffer_size
-                || flush_max_interval_has_passed(&buffer, &client, last_flushed_at)
-            {
-                client.send_traces(buffer.drain(..).collect()).await;
-                last_flushed_at = SystemTime::now();
             }
         }
+    })
+}
 
-        fn flush_max_interval_has_passed<T>(
-            buffer: &Vec<T>,
-            client: &Client,
-            last_flushed_at: SystemTime,
-        ) -> bool {
-            buffer.len() > 0
-                && SystemTime::now().duration_since(last_flushed_at).unwrap()
-                    > client.buffer_flush_max_interval
+/// Sends the traces in batches of at most `buffer_size` traces and `max_payload_bytes` estimated bytes.
+async fn send_in_batches(client: &Client, traces: Vec<Trace>) {
+    let mut batch = Vec::new();
+    let mut batch_bytes = 0;
+    for trace in traces {
+        let trace_bytes = estimated_size(&trace);
+        if !batch.is_empty()
+            && (batch.len() >= client.buffer_size
+                || batch_bytes + trace_bytes > client.max_payload_bytes)
+        {
+            client.clone().send_batch(std::mem::take(&mut batch)).await;
+            batch_bytes = 0;
         }
-    });
+        batch_bytes += trace_bytes;
+        batch.push(trace);
+    }
+    if !batch.is_empty() {
+        client.clone().send_batch(batch).await;
+    }
 }
 
-fn serialize_as_msgpack(traces: Vec<Vec<RawSpan>>) -> Vec<u8> {
-    // this function uses a hack over rpm_serde library,
-    // because the lib does not work when the struct is wrapped in a array,
-    // so it manually encode the array, and then serialize each entity in a loop
+/// Estimates the size in bytes of the encoded trace from the length of its strings, without encoding it.
+fn estimated_size(trace: &Trace) -> usize {
+    // the ids, timestamps and keys of the span fields
+    const SPAN_OVERHEAD: usize = 128;
+    let info_len = |strings: &[&str]| {
+        strings
+            .iter()
+            .map(|string| string.len() + 32)
+            .sum::<usize>()
+    };
+    trace
+        .spans
+        .iter()
+        .map(|span| {
+            let mut size = SPAN_OVERHEAD + span.name.len() + span.resource.len();
+            size += span.service.as_ref().map_or(0, String::len);
+            size += span
+                .tags
+                .iter()
+                .map(|(key, value)| key.len() + value.len())
+                .sum::<usize>();
+            size += span.metrics.keys().map(|key| key.len() + 9).sum::<usize>();
+            if let Some(http) = &span.http {
+                size += info_len(&[&http.url, &http.method, &http.status_code]);
+            }
+            if let Some(error) = &span.error {
+                size += info_len(&[&error.r#type, &error.msg, &error.stack]);
+            }
+            if let Some(sql) = &span.sql {
+                size += info_len(&[&sql.query, &sql.rows, &sql.db]);
+            }
+            if let Some(messaging) = &span.messaging {
+                size += info_len(&[
+                    &messaging.system,
+                    &messaging.destination,
+                    &messaging.operation,
+                ]);
+            }
+            if let Some(cache) = &span.cache {
+                size += info_len(&[&cache.system, &cache.command, &cache.key]);
+            }
+            size
+        })
+        .sum()
+}
 
-    let mut buf = Vec::new();
+/// Where the datadog agent listens, resolved from the config.
+#[derive(Debug, Clone, PartialEq)]
+enum AgentAddress {
+    /// Base url of the agent, like `http://localhost:8126`.
+    Tcp(String),
+    UnixSocket(PathBuf),
+    /// Full url of the traces endpoint, from `Config::endpoint_url`.
+    Endpoint(String),
+}
 
-    encode::write_array_len(&mut buf, traces.len() as u32).unwrap();
-    for spans in traces {
-        encode::write_array_len(&mut buf, spans.len() as u32).unwrap();
-        for span in spans {
-            let mut se = rmps::Serializer::new(&mut buf).with_struct_map();
-            span.serialize(&mut se).unwrap();
+impl AgentAddress {
+    fn from_config(config: &Config) -> Result<AgentAddress, ConfigError> {
+        if let Some(endpoint_url) = &config.endpoint_url {
+            let uri = endpoint_url
+                .parse::<Uri>()
+                .map_err(|_| ConfigError::InvalidAgentUrl(endpoint_url.clone()))?;
+            return match (uri.scheme_str(), uri.authority()) {
+                (Some("http"), Some(_)) | (Some("https"), Some(_)) => {
+                    Ok(AgentAddress::Endpoint(endpoint_url.clone()))
+                }
+                _ => Err(ConfigError::InvalidAgentUrl(endpoint_url.clone())),
+            };
+        }
+        match (&config.socket_path, &config.agent_url) {
+            (Some(socket_path), _) => Ok(AgentAddress::UnixSocket(socket_path.clone())),
+            (None, Some(agent_url)) => AgentAddress::parse(agent_url),
+            (None, None) => Ok(AgentAddress::Tcp(format!(
+                "http://{}:{}",
+                config.host, config.port
+            ))),
         }
     }
-    buf
-}
 
-fn fill_meta(span: &Span, env: Option<String>) -> HashMap<String, String> {
-    let mut meta = HashMap::new();
-    if let Some(env) = env {
-        meta.insert("env".to_string(), env);
+    /// Parses an agent url, either `http[s]://host[:port]` or `unix:///path/to/socket`.
+    fn parse(agent_url: &str) -> Result<AgentAddress, ConfigError> {
+        let invalid = || ConfigError::InvalidAgentUrl(agent_url.to_string());
+
+        if let Some(socket_path) = agent_url.strip_prefix("unix://") {
+            return match socket_path {
+                path if path.starts_with('/') && path.len() > 1 => {
+                    Ok(AgentAddress::UnixSocket(PathBuf::from(path)))
+                }
+                _ => Err(invalid()),
+            };
+        }
+
+        let base_url = agent_url.trim_end_matches('/');
+        let uri = base_url.parse::<Uri>().map_err(|_| invalid())?;
+        match (uri.scheme_str(), uri.authority(), uri.query()) {
+            (Some("http"), Some(_), None) | (Some("https"), Some(_), None) => {
+                Ok(AgentAddress::Tcp(base_url.to_string()))
+            }
+            _ => Err(invalid()),
+        }
     }
 
-    if let Some(http) = &span.http {
-        meta.insert("http.status_code".to_string(), http.status_code.clone());
-        meta.insert("http.method".to_string(), http.method.clone());
-        meta.insert("http.url".to_string(), http.url.clone());
+    fn traces_endpoint(&self, version: ApiVersion) -> Endpoint {
+        let base_url = match self {
+            AgentAddress::Tcp(base_url) => base_url.clone(),
+            // the host is ignored by the connector, the request goes to the socket
+            AgentAddress::UnixSocket(_) => "http://localhost".to_string(),
+            AgentAddress::Endpoint(url) => {
+                return Endpoint {
+                    base_url: url.clone(),
+                    version,
+                    verbatim: true,
+                }
+            }
+        };
+        Endpoint {
+            base_url,
+            version,
+            verbatim: false,
+        }
     }
-    if let Some(error) = &span.error {
-        meta.insert("error.type".to_string(), error.r#type.clone());
-        meta.insert("error.msg".to_string(), error.msg.clone());
-        meta.insert("error.stack".to_string(), error.stack.clone());
+
+    /// Base urls of this address and of the fallback agent urls, in the order to try them.
+    fn with_fallbacks(&self, fallback_agent_urls: &[String]) -> Result<Vec<String>, ConfigError> {
+        let base_url = match self {
+            AgentAddress::Tcp(base_url) => base_url.clone(),
+            AgentAddress::Endpoint(url) => return Ok(vec![url.clone()]),
+            AgentAddress::UnixSocket(_) if fallback_agent_urls.is_empty() => return Ok(Vec::new()),
+            AgentAddress::UnixSocket(_) => return Err(ConfigError::UnixSocketFallback),
+        };
+        let mut base_urls = vec![base_url];
+        for agent_url in fallback_agent_urls {
+            match AgentAddress::parse(agent_url)? {
+                AgentAddress::Tcp(base_url) => base_urls.push(base_url),
+                AgentAddress::UnixSocket(_) | AgentAddress::Endpoint(_) => {
+                    return Err(ConfigError::InvalidAgentUrl(agent_url.clone()))
+                }
+            }
+        }
+        Ok(base_urls)
+    }
+
+    fn connector(&self) -> Result<AgentConnector, ConfigError> {
+        match self {
+            AgentAddress::Tcp(_) | AgentAddress::Endpoint(_) => {
+                Ok(AgentConnector::Tcp(HttpConnector::new()))
+            }
+            #[cfg(unix)]
+            AgentAddress::UnixSocket(socket_path) => Ok(AgentConnector::Unix(socket_path.clone())),
+            #[cfg(not(unix))]
+            AgentAddress::UnixSocket(_) => Err(ConfigError::UnixSocketUnsupported),
+        }
+    }
+}
+
+/// The traces endpoint of the datadog agent.
+///
+/// It starts at the newest api version and is downgraded when the agent does not support it.
+#[derive(Debug, Clone, PartialEq)]
+struct Endpoint {
+    base_url: String,
+    version: ApiVersion,
+    /// The base url is the full url of the endpoint, it is never downgraded.
+    verbatim: bool,
+}
+
+impl Endpoint {
+    fn url(&self) -> String {
+        if self.verbatim {
+            return self.base_url.clone();
+        }
+        format!("{}/{}/traces", self.base_url, self.version.path())
+    }
+
+    /// Older agents answer `404` for an unknown api version, and `415` for an unknown payload format.
+    fn should_downgrade(status: StatusCode) -> bool {
+        status == StatusCode::NOT_FOUND || status == StatusCode::UNSUPPORTED_MEDIA_TYPE
+    }
+}
+
+/// Compression of the payloads sent to the datadog agent.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum Compression {
+    #[default]
+    None,
+    Gzip,
+    /// The zlib format, as expected for the `deflate` content encoding.
+    Deflate,
+}
+
+impl Compression {
+    fn compress(self, payload: Vec<u8>) -> io::Result<Vec<u8>> {
+        match self {
+            Compression::None => Ok(payload),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&payload)?;
+                encoder.finish()
+            }
+            Compression::Deflate => {
+                let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(&payload)?;
+                encoder.finish()
+            }
+        }
+    }
+
+    fn content_encoding(self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("gzip"),
+            Compression::Deflate => Some("deflate"),
+        }
+    }
+}
+
+/// Versions of the agent traces api, from the newest to the oldest.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ApiVersion {
+    /// `/v0.5/traces`, deduplicating the span strings in a string table.
+    #[default]
+    V05,
+    /// `/v0.4/traces`
+    V04,
+    /// `/v0.3/traces`
+    V03,
+}
+
+impl ApiVersion {
+    fn path(self) -> &'static str {
+        match self {
+            ApiVersion::V05 => "v0.5",
+            ApiVersion::V04 => "v0.4",
+            ApiVersion::V03 => "v0.3",
+        }
+    }
+
+    fn downgrade(self) -> Option<ApiVersion> {
+        match self {
+            ApiVersion::V05 => Some(ApiVersion::V04),
+            ApiVersion::V04 => Some(ApiVersion::V03),
+            ApiVersion::V03 => None,
+        }
+    }
+
+    fn serialize(self, traces: &[Vec<RawSpan>]) -> Result<Vec<u8>, rmps::encode::Error> {
+        match self {
+            ApiVersion::V05 => serialize_as_msgpack_v05(traces),
+            ApiVersion::V04 | ApiVersion::V03 => serialize_as_msgpack(traces),
+        }
+    }
+}
+
+fn serialize_as_msgpack(traces: &[Vec<RawSpan>]) -> Result<Vec<u8>, rmps::encode::Error> {
+    // the spans are maps of their field names, as the agent expects
+    let mut buf = Vec::new();
+    traces.serialize(&mut rmps::Serializer::new(&mut buf).with_struct_map())?;
+    Ok(buf)
+}
+
+/// Strings of a v0.5 payload, the spans refer to them by their index in the table.
+struct StringTable<'a> {
+    strings: Vec<&'a str>,
+    indexes: HashMap<&'a str, u32>,
+}
+
+impl<'a> StringTable<'a> {
+    fn new() -> Self {
+        let mut table = StringTable {
+            strings: Vec::new(),
+            indexes: HashMap::new(),
+        };
+        // the agent expects the empty string as the first entry
+        table.intern("");
+        table
+    }
+
+    fn intern(&mut self, string: &'a str) -> u64 {
+        let strings = &mut self.strings;
+        let index = *self.indexes.entry(string).or_insert_with(|| {
+            strings.push(string);
+            strings.len() as u32 - 1
+        });
+        u64::from(index)
+    }
+}
+
+fn serialize_as_msgpack_v05(traces: &[Vec<RawSpan>]) -> Result<Vec<u8>, rmps::encode::Error> {
+    // the v0.5 payload is an array of the string table and the traces,
+    // where every span is an array of its fields with the strings replaced by their index
+
+    // the v0.5 span has no span links field, they are sent as json in the `_dd.span_links` meta
+    let span_links = traces
+        .iter()
+        .map(|spans| {
+            spans
+                .iter()
+                .map(|span| match span.span_links.as_slice() {
+                    [] => None,
+                    links => Some(span_links_json(links)),
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    let mut strings = StringTable::new();
+    let mut traces_buf = Vec::new();
+
+    encode::write_array_len(&mut traces_buf, traces.len() as u32)?;
+    for (spans, span_links) in traces.iter().zip(&span_links) {
+        encode::write_array_len(&mut traces_buf, spans.len() as u32)?;
+        for (span, links) in spans.iter().zip(span_links) {
+            let buf = &mut traces_buf;
+            encode::write_array_len(buf, 12)?;
+            encode::write_uint(buf, strings.intern(&span.service))?;
+            encode::write_uint(buf, strings.intern(&span.name))?;
+            encode::write_uint(buf, strings.intern(&span.resource))?;
+            encode::write_uint(buf, span.trace_id)?;
+            encode::write_uint(buf, span.span_id)?;
+            encode::write_uint(buf, span.parent_id.unwrap_or(0))?;
+            encode::write_sint(buf, span.start as i64)?;
+            encode::write_sint(buf, span.duration as i64)?;
+            encode::write_sint(buf, i64::from(span.error))?;
+            encode::write_map_len(buf, (span.meta.len() + links.iter().len()) as u32)?;
+            for (key, value) in &span.meta {
+                encode::write_uint(buf, strings.intern(key))?;
+                encode::write_uint(buf, strings.intern(value))?;
+            }
+            if let Some(links) = links {
+                encode::write_uint(buf, strings.intern("_dd.span_links"))?;
+                encode::write_uint(buf, strings.intern(links))?;
+            }
+            encode::write_map_len(buf, span.metrics.len() as u32)?;
+            for (key, value) in &span.metrics {
+                encode::write_uint(buf, strings.intern(key))?;
+                encode::write_f64(buf, *value)?;
+            }
+            encode::write_uint(buf, strings.intern(&span.r#type))?;
+        }
+    }
+
+    let mut buf = Vec::new();
+    encode::write_array_len(&mut buf, 2)?;
+    encode::write_array_len(&mut buf, strings.strings.len() as u32)?;
+    for string in &strings.strings {
+        encode::write_str(&mut buf, string)?;
+    }
+    buf.extend(traces_buf);
+    Ok(buf)
+}
+
+/// Encodes the span links like the datadog tracers do in the `_dd.span_links` meta,
+/// with the ids as hexadecimal strings.
+fn span_links_json(links: &[RawSpanLink]) -> String {
+    let mut json = String::from("[");
+    for (index, link) in links.iter().enumerate() {
+        if index > 0 {
+            json.push(',');
+        }
+        json.push_str(&format!(
+            "{{\"trace_id\":\"{:016x}{:016x}\",\"span_id\":\"{:016x}\"",
+            link.trace_id_high, link.trace_id, link.span_id
+        ));
+        if !link.attributes.is_empty() {
+            json.push_str(",\"attributes\":{");
+            for (index, (key, value)) in link.attributes.iter().enumerate() {
+                if index > 0 {
+                    json.push(',');
+                }
+                push_json_string(&mut json, key);
+                json.push(':');
+                push_json_string(&mut json, value);
+            }
+            json.push('}');
+        }
+        json.push('}');
+    }
+    json.push(']');
+    json
+}
+
+fn push_json_string(json: &mut String, value: &str) {
+    json.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => json.push_str(&format!("\\u{:04x}", c as u32)),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+}
+
+/// Maximum length of the `cache.key` meta, keys can hold ids or user data.
+const MAX_CACHE_KEY_LEN: usize = 100;
+
+fn fill_meta(
+    span: &Span,
+    env: Option<&str>,
+    version: Option<&str>,
+    origin: Option<&str>,
+    global_tags: &HashMap<String, String>,
+    obfuscate_sql: bool,
+) -> BTreeMap<String, String> {
+    let mut meta = global_tags
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect::<BTreeMap<_, _>>();
+    if let Some(env) = env {
+        meta.insert("env".to_string(), env.to_string());
+    }
+    if let Some(version) = version {
+        meta.insert("version".to_string(), version.to_string());
+    }
+    if let Some(origin) = origin {
+        meta.insert("_dd.origin".to_string(), origin.to_string());
+    }
+
+    if let Some(http) = &span.http {
+        meta.insert("http.status_code".to_string(), http.status_code.clone());
+        meta.insert("http.method".to_string(), http.method.clone());
+        meta.insert("http.url".to_string(), http.url.clone());
+        if let Some(size) = http.request_size {
+            meta.insert("http.request.content_length".to_string(), size.to_string());
+        }
+        if let Some(size) = http.response_size {
+            meta.insert("http.response.content_length".to_string(), size.to_string());
+        }
+        if let Some(route) = &http.route {
+            meta.insert("http.route".to_string(), route.clone());
+        }
+    }
+    if let Some(error) = &span.error {
+        meta.insert("error.type".to_string(), error.r#type.clone());
+        meta.insert("error.msg".to_string(), error.msg.clone());
+        meta.insert("error.stack".to_string(), error.stack.clone());
+    }
+    if let Some(sql) = &span.sql {
+        let query = if obfuscate_sql {
+            obfuscate_sql_query(&sql.query)
+        } else {
+            sql.query.clone()
+        };
+        meta.insert("sql.query".to_string(), query);
+        meta.insert("sql.rows".to_string(), sql.rows.clone());
+        meta.insert("sql.db".to_string(), sql.db.clone());
+    }
+    if let Some(messaging) = &span.messaging {
+        meta.insert("messaging.system".to_string(), messaging.system.clone());
+        meta.insert(
+            "messaging.destination".to_string(),
+            messaging.destination.clone(),
+        );
+        meta.insert(
+            "messaging.operation".to_string(),
+            messaging.operation.clone(),
+        );
+    }
+    if let Some(cache) = &span.cache {
+        meta.insert("cache.system".to_string(), cache.system.clone());
+        let command_key = if cache.system == "redis" {
+            "redis.raw_command"
+        } else {
+            "cache.command"
+        };
+        meta.insert(command_key.to_string(), cache.command.clone());
+        if !cache.key.is_empty() {
+            let mut key = cache.key.clone();
+            truncate_tag_value(&mut key, MAX_CACHE_KEY_LEN);
+            meta.insert("cache.key".to_string(), key);
+        }
+    }
+    for (key, value) in &span.tags {
+        meta.insert(key.to_string(), value.to_string());
+    }
+    meta
+}
+
+/// Replaces the string and numeric literals of the query with `?`, and lists of literals with a single `?`.
+///
+/// Double quoted identifiers, `$1` placeholders and digits inside identifiers, like `table1`, are kept.
+fn obfuscate_sql_query(query: &str) -> String {
+    let chars = query.chars().collect::<Vec<_>>();
+    let mut obfuscated = String::with_capacity(query.len());
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        if c == '\'' {
+            // a quote inside a string literal is escaped by doubling it
+            i += 1;
+            while i < chars.len() {
+                if chars[i] == '\'' {
+                    if chars.get(i + 1) == Some(&'\'') {
+                        i += 1;
+                    } else {
+                        break;
+                    }
+                }
+                i += 1;
+            }
+            obfuscated.push('?');
+            i += 1;
+        } else if c == '"' {
+            let end = chars[i + 1..]
+                .iter()
+                .position(|&c| c == '"')
+                .map_or(chars.len(), |end| i + end + 2);
+            obfuscated.extend(&chars[i..end]);
+            i = end;
+        } else if c.is_ascii_digit() {
+            let in_identifier = matches!(obfuscated.chars().last(), Some(last)
+                if last.is_alphanumeric() || last == '_' || last == '$');
+            while i < chars.len() && (chars[i].is_ascii_alphanumeric() || chars[i] == '.') {
+                if in_identifier {
+                    obfuscated.push(chars[i]);
+                }
+                i += 1;
+            }
+            if !in_identifier {
+                obfuscated.push('?');
+            }
+        } else if c == ')' {
+            collapse_literal_list(&mut obfuscated);
+            obfuscated.push(c);
+            i += 1;
+        } else {
+            obfuscated.push(c);
+            i += 1;
+        }
+    }
+    obfuscated
+}
+
+/// Replaces a list of `?` after the last opening parenthesis, like `(?, ?, ?`, with a single `?`.
+fn collapse_literal_list(obfuscated: &mut String) {
+    if let Some(start) = obfuscated.rfind('(') {
+        let list = &obfuscated[start + 1..];
+        let is_literal_list =
+            list.contains(',') && list.split(',').all(|literal| literal.trim() == "?");
+        if is_literal_list {
+            obfuscated.truncate(start + 1);
+            obfuscated.push('?');
+        }
+    }
+}
+
+/// Keeps `rate` of the trace ids, by their low 64 bits, spreading the sequential ones with the knuth multiplicative hash.
+fn is_sampled(trace_id: u64, rate: f64) -> bool {
+    const KNUTH_FACTOR: u64 = 1_111_111_111_111_111_111;
+    trace_id.wrapping_mul(KNUTH_FACTOR) < (rate * u64::MAX as f64) as u64
+}
+
+fn fill_metrics(span: &Span, priority: Priority) -> BTreeMap<String, f64> {
+    let mut metrics = span
+        .metrics
+        .iter()
+        .map(|(key, value)| (key.clone(), *value))
+        .collect::<BTreeMap<_, _>>();
+    metrics.insert(
+        "_sampling_priority_v1".to_string(),
+        f64::from(priority.value()),
+    );
+    if span.measured {
+        metrics.insert("_dd.measured".to_string(), 1.0);
+    }
+    if let Some(rate) = span.analytics_rate {
+        metrics.insert("_dd1.sr.eausr".to_string(), rate.clamp(0.0, 1.0));
+    }
+    metrics
+}
+
+/// Truncates the value to `max_len` bytes, ending it with `...[truncated]`.
+fn truncate_tag_value(value: &mut String, max_len: usize) {
+    const SUFFIX: &str = "...[truncated]";
+    if value.len() <= max_len {
+        return;
+    }
+    let mut len = max_len.saturating_sub(SUFFIX.len());
+    while !value.is_char_boundary(len) {
+        len -= 1;
+    }
+    value.truncate(len);
+    if len + SUFFIX.len() <= max_len {
+        value.push_str(SUFFIX);
+    }
+}
+
+/// Splits a 128-bit trace id in its high and low 64 bits.
+fn split_trace_id(trace_id: u128) -> (u64, u64) {
+    ((trace_id >> 64) as u64, trace_id as u64)
+}
+
+fn map_to_raw_spans(
+    trace: &Trace,
+    env: Option<&str>,
+    version: Option<&str>,
+    service: &str,
+    global_tags: &HashMap<String, String>,
+    obfuscate_sql: bool,
+) -> Vec<RawSpan> {
+    let mut traces = Vec::new();
+    for span in &trace.spans {
+        let mut meta = fill_meta(
+            span,
+            env,
+            version,
+            trace.origin.as_deref(),
+            global_tags,
+            obfuscate_sql,
+        );
+        let (trace_id_high, trace_id) = split_trace_id(trace.id);
+        if trace_id_high != 0 {
+            meta.insert("_dd.p.tid".to_string(), format!("{:016x}", trace_id_high));
+        }
+        traces.push(RawSpan {
+            service: span.service.clone().unwrap_or_else(|| service.to_string()),
+            trace_id,
+            span_id: span.id,
+            name: span.name.clone(),
+            resource: span.resource.clone(),
+            parent_id: span.parent_id,
+            start: time_to_nanos(span.start),
+            duration: duration_to_nanos(span.duration),
+            error: if span.error.is_some() { 1 } else { 0 },
+            r#type: span.r#type.to_string(),
+            meta,
+            metrics: fill_metrics(span, trace.priority),
+            span_links: span
+                .links
+                .iter()
+                .map(|link| RawSpanLink {
+                    trace_id: split_trace_id(link.trace_id).1,
+                    trace_id_high: split_trace_id(link.trace_id).0,
+                    span_id: link.span_id,
+                    attributes: link
+                        .attributes
+                        .iter()
+                        .map(|(key, value)| (key.clone(), value.clone()))
+                        .collect(),
+                })
+                .collect(),
+        });
+    }
+    traces
+}
+
+/// Nanoseconds since the UNIX epoch, `0` for a time before the epoch.
+fn time_to_nanos(time: SystemTime) -> u64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration_to_nanos(duration),
+        Err(_) => {
+            warn!("span start {:?} is before the UNIX epoch, sending 0", time);
+            0
+        }
+    }
+}
+
+fn duration_to_nanos(duration: Duration) -> u64 {
+    duration
+        .as_secs()
+        .saturating_mul(1_000_000_000)
+        .saturating_add(u64::from(duration.subsec_nanos()))
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate rand;
+
+    use super::*;
+    use crate::clock::MockClock;
+    use crate::testing::{
+        recording_client, recording_client_with_clock, RecordingConnector, OK_RESPONSE,
+    };
+
+    use hyper::body::Bytes;
+    use hyper::server::conn::Http;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::Server;
+    use rand::Rng;
+    use serde_json::json;
+    use std::io::Read;
+    use std::sync::{Arc, Mutex};
+
+    #[tokio::test]
+    #[ignore]
+    async fn test_send_trace() {
+        let config = Config {
+            service: String::from("service_name"),
+            ..Default::default()
+        };
+        let client = Client::new(config);
+        let trace = a_trace();
+        client.send_trace(trace).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_send_trace_queue_full() {
+        let config = Config {
+            service: String::from("service_name"),
+            buffer_queue_capacity: 1,
+            ..Default::default()
+        };
+        let client = Client::new(config);
+
+        assert!(client.send_trace(a_trace()).is_ok());
+        assert!(matches!(
+            client.send_trace(a_trace()),
+            Err(TraceError::QueueFull(_))
+        ));
+
+        let stats = client.stats();
+        assert_eq!(stats.enqueued, 1);
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(client.dropped_traces(), 1);
+        assert_eq!(client.queue_len(), 1);
+        assert_eq!(client.queue_capacity(), 1);
+
+        let client = Client::new(Config {
+            buffer_queue_capacity: 200_000,
+            ..Default::default()
+        });
+        assert_eq!(client.queue_capacity(), 200_000);
+    }
+
+    #[tokio::test]
+    async fn test_on_drop_queue_full() {
+        let dropped_ids = Arc::new(Mutex::new(Vec::new()));
+        let on_drop_ids = dropped_ids.clone();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            buffer_queue_capacity: 2,
+            on_drop: Some(Arc::new(move |trace: &Trace| {
+                on_drop_ids.lock().unwrap().push(trace.id)
+            })),
+            ..Default::default()
+        });
+
+        for id in 1..=5 {
+            let mut trace = a_trace();
+            trace.id = id;
+            let _ = client.send_trace(trace);
+        }
+
+        assert_eq!(*dropped_ids.lock().unwrap(), vec![3, 4, 5]);
+        assert_eq!(client.stats().dropped, 3);
+    }
+
+    #[tokio::test]
+    async fn test_send_trace_async_waits_for_the_queue() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            buffer_queue_capacity: 1,
+            ..Default::default()
+        });
+
+        assert!(client.send_trace(a_trace()).is_ok());
+        // the queue is full until the consumer task takes the first trace
+        client.send_trace_async(a_trace()).await.unwrap();
+        client.flush().await;
+
+        assert_eq!(received_trace_count(&received), 2);
+        let stats = client.stats();
+        assert_eq!((stats.enqueued, stats.dropped), (2, 0));
+
+        client.clone().shutdown().await;
+        assert!(matches!(
+            client.send_trace_async(a_trace()).await,
+            Err(TraceError::Disconnected)
+        ));
+        assert_eq!(client.stats().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_queue_len() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            buffer_flush_max_interval: Duration::from_secs(60),
+            ..Default::default()
+        });
+        assert_eq!(client.queue_len(), 0);
+
+        // the consumer task does not run before this task yields
+        for queued in 1..=10 {
+            client.send_trace(a_trace()).unwrap();
+            assert_eq!(client.queue_len(), queued);
+        }
+        assert_eq!(client.stats().enqueued, 10);
+
+        client.flush().await;
+        assert_eq!(client.queue_len(), 0);
+        assert_eq!(received_trace_count(&received), 10);
+    }
+
+    #[tokio::test]
+    async fn test_send_traces() {
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            buffer_queue_capacity: 3,
+            ..Default::default()
+        });
+
+        let traces = (0..5).map(|_| a_trace()).collect();
+        assert_eq!(client.send_traces(traces), 3);
+        let stats = client.stats();
+        assert_eq!((stats.enqueued, stats.dropped), (3, 2));
+        assert_eq!(client.send_traces(Vec::new()), 0);
+    }
+
+    #[tokio::test]
+    async fn test_is_running() {
+        let client = Client::new(Config::default());
+        assert!(client.is_running());
+        client.clone().shutdown().await;
+        assert!(!client.is_running());
+
+        // nothing listens on port 1, so the trace is handed to the panicking `on_drop`
+        let client = Client::new(Config {
+            agent_url: Some("http://127.0.0.1:1".to_string()),
+            max_retries: 0,
+            on_drop: Some(Arc::new(|_: &Trace| panic!("on_drop failed"))),
+            ..Default::default()
+        });
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+        let stopped = tokio::time::timeout(Duration::from_secs(5), async {
+            while client.is_running() {
+                tokio::time::delay_for(Duration::from_millis(1)).await;
+            }
+        });
+        assert!(
+            stopped.await.is_ok(),
+            "the consumer task is still running after the on_drop panic"
+        );
+        assert_eq!(client.stats().enqueued, 1);
+    }
+
+    #[test]
+    fn test_disabled_client() {
+        // no tokio runtime, the disabled client must not spawn its buffer consumer task
+        let client = Client::new(Config {
+            enabled: false,
+            ..Default::default()
+        });
+
+        assert!(client.send_trace(a_trace()).is_ok());
+        assert!(client.consumer_task.lock().unwrap().is_none());
+        assert!(!client.is_running());
+        assert_eq!(client.stats(), ClientStats::default());
+
+        let client = Client::disabled();
+        assert!(client.send_trace(a_trace()).is_ok());
+        assert!(client.consumer_task.lock().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_send_trace_validation() {
+        let mut trace = a_trace();
+        trace.spans[0].parent_id = Some(trace.spans[0].id.wrapping_add(1));
+
+        let client = Client::new(Config {
+            validate_traces: true,
+            ..Default::default()
+        });
+        assert!(matches!(
+            client.send_trace(trace.clone()),
+            Err(TraceError::UnknownParentId { .. })
+        ));
+        assert_eq!(client.queue_len(), 0);
+
+        let client = Client::new(Config::default());
+        assert!(client.send_trace(trace).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_send_trace_sampling() {
+        let sampled = (1..=1000).filter(|id| is_sampled(*id, 0.5)).count();
+        assert!(sampled > 400 && sampled < 600, "{} sampled", sampled);
+        assert_eq!((1..=1000).filter(|id| is_sampled(*id, 0.0)).count(), 0);
+
+        // nothing listens on port 1, so the sent traces are handed to `on_drop`
+        let dropped = Arc::new(Mutex::new(Vec::new()));
+        let on_drop_traces = dropped.clone();
+        let client = Client::new(Config {
+            agent_url: Some("http://127.0.0.1:1".to_string()),
+            max_retries: 0,
+            sample_rate: 0.5,
+            on_drop: Some(Arc::new(move |trace: &Trace| {
+                on_drop_traces.lock().unwrap().push(trace.clone())
+            })),
+            ..Default::default()
+        });
+        let priorities = [
+            Priority::AutoReject,
+            Priority::AutoKeep,
+            Priority::UserReject,
+            Priority::UserKeep,
+        ];
+        for id in 1..=100u64 {
+            let mut trace = a_trace();
+            trace.id = u128::from(id);
+            trace.priority = priorities[id as usize % priorities.len()];
+            client.send_trace(trace).unwrap();
+        }
+        client.flush().await;
+
+        let dropped = dropped.lock().unwrap();
+        assert_eq!(
+            dropped.len(),
+            (1..=100).filter(|id| is_sampled(*id, 0.5)).count()
+        );
+        for trace in dropped.iter() {
+            assert!(is_sampled(trace.id as u64, 0.5));
+            assert_eq!(trace.spans[0].metrics["_dd.agent_psr"], 0.5);
+            let priority = match priorities[trace.id as usize % priorities.len()] {
+                Priority::UserReject => Priority::UserReject,
+                Priority::UserKeep => Priority::UserKeep,
+                _ => Priority::AutoKeep,
+            };
+            assert_eq!(trace.priority, priority);
+            let raw_spans = map_to_raw_spans(trace, None, None, "", &HashMap::new(), false);
+            assert_eq!(
+                raw_spans[0].metrics["_sampling_priority_v1"],
+                f64::from(priority.value())
+            );
+        }
+
+        assert!(matches!(
+            Client::try_new(Config {
+                sample_rate: 1.5,
+                ..Default::default()
+            }),
+            Err(ConfigError::InvalidSampleRate(_))
+        ));
+    }
+
+    #[test]
+    fn test_trace_validate() {
+        let mut trace = a_trace();
+        let root_id = trace.spans[0].id;
+        let mut child = trace.spans[0].clone();
+        child.id = root_id.wrapping_add(1);
+        child.parent_id = Some(root_id);
+        trace.spans.push(child);
+        assert!(trace.validate().is_ok());
+
+        let mut orphan = trace.clone();
+        orphan.spans[1].parent_id = Some(root_id.wrapping_add(2));
+        assert!(matches!(
+            orphan.validate(),
+            Err(TraceError::UnknownParentId { span_id, parent_id })
+                if span_id == root_id.wrapping_add(1) && parent_id == root_id.wrapping_add(2)
+        ));
+
+        let mut own_parent = trace.clone();
+        own_parent.spans[0].parent_id = Some(root_id);
+        assert!(matches!(
+            own_parent.validate(),
+            Err(TraceError::UnknownParentId { .. })
+        ));
+
+        let mut duplicate = trace;
+        duplicate.spans[1].id = root_id;
+        assert!(matches!(
+            duplicate.validate(),
+            Err(TraceError::DuplicateSpanId(id)) if id == root_id
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_sampling_rates() {
+        let (agent_url, _) = spawn_agent();
+        let client = Client::new(Config {
+            agent_url: Some(agent_url),
+            ..Default::default()
+        });
+        assert!(client.sampling_rates().is_empty());
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        let rates = client.sampling_rates();
+        assert_eq!(rates.len(), 2);
+        assert_eq!(rates["service:,env:"], 1.0);
+        assert_eq!(rates["service:service_name,env:staging"], 0.25);
+
+        // a response without rates, like the v0.3 api "OK", keeps the previous ones
+        client.update_sampling_rates(Body::from("OK")).await;
+        client.update_sampling_rates(Body::from("{}")).await;
+        assert_eq!(client.sampling_rates(), rates);
+    }
+
+    #[tokio::test]
+    async fn test_send_trace_through_arc() {
+        let (agent_url, received) = spawn_agent();
+        let client = Arc::new(Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            buffer_flush_max_interval: Duration::from_secs(60),
+            ..Default::default()
+        }));
+
+        let tasks = (0..2)
+            .map(|_| {
+                let client = client.clone();
+                tokio::spawn(async move {
+                    for _ in 0..3 {
+                        client.send_trace(a_trace()).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        client.flush().await;
+
+        assert_eq!(received_trace_count(&received), 6);
+    }
+
+    #[tokio::test]
+    async fn test_shutdown_sends_buffered_traces() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            buffer_size: 2,
+            buffer_flush_max_interval: Duration::from_secs(60),
+            ..Default::default()
+        });
+
+        for _ in 0..5 {
+            client.send_trace(a_trace()).unwrap();
+        }
+        client.clone().shutdown().await;
+
+        assert_eq!(received_trace_count(&received), 5);
+        assert!(client.consumer_task.lock().unwrap().is_none());
+        assert!(matches!(
+            client.send_trace(a_trace()),
+            Err(TraceError::Disconnected)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_flush() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            buffer_flush_max_interval: Duration::from_secs(60),
+            ..Default::default()
+        });
+
+        client.flush().await;
+        assert_eq!(received.lock().unwrap().len(), 0);
+
+        for _ in 0..3 {
+            client.send_trace(a_trace()).unwrap();
+        }
+        client.flush().await;
+        assert_eq!(received_trace_count(&received), 3);
+        assert_eq!(
+            client.stats(),
+            ClientStats {
+                enqueued: 3,
+                sent: 3,
+                dropped: 0,
+                send_errors: 0,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_buffer_size_flushes_between_ticks() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            buffer_size: 2,
+            buffer_flush_max_interval: Duration::from_secs(60),
+            ..Default::default()
+        });
+
+        for _ in 0..5 {
+            client.send_trace(a_trace()).unwrap();
+        }
+        // the size trigger sends the first 4 traces without waiting for the flush interval
+        for _ in 0..100 {
+            if received_trace_count(&received) >= 4 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert!(received_trace_count(&received) >= 4);
+
+        client.flush().await;
+        assert_eq!(received_trace_count(&received), 5);
+        for req in received.lock().unwrap().iter() {
+            assert!(req.headers()["X-Datadog-Trace-Count"] <= "2");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_payload_size_flushes_between_ticks() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            max_payload_bytes: estimated_size(&a_trace()) * 2,
+            buffer_flush_max_interval: Duration::from_secs(60),
+            ..Default::default()
+        });
+
+        for _ in 0..5 {
+            client.send_trace(a_trace()).unwrap();
+        }
+        // the payload size trigger sends the first 4 traces without waiting for the flush interval
+        for _ in 0..100 {
+            if received_trace_count(&received) >= 4 {
+                break;
+            }
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+        }
+        assert!(received_trace_count(&received) >= 4);
+
+        client.flush().await;
+        assert_eq!(received_trace_count(&received), 5);
+        for req in received.lock().unwrap().iter() {
+            assert!(req.headers()["X-Datadog-Trace-Count"] <= "2");
+        }
+    }
+
+    #[test]
+    fn test_estimated_size() {
+        let mut trace = a_trace();
+        let size = estimated_size(&trace);
+        assert!(size > 0);
+
+        trace.spans[0].error = Some(ErrorInfo {
+            r#type: "panic".to_string(),
+            msg: "boom".to_string(),
+            stack: "frame\n".repeat(1000),
+        });
+        assert!(estimated_size(&trace) > size + 6000);
+
+        trace.spans.push(Span::new("db", "SELECT"));
+        assert!(estimated_size(&trace) > size + 6000 + 128);
+        assert_eq!(estimated_size(&Trace::new(Priority::AutoKeep)), 0);
+    }
+
+    #[tokio::test]
+    async fn test_empty_traces_are_not_sent() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            ..Default::default()
+        });
+
+        client.send_trace(Trace::new(Priority::AutoKeep)).unwrap();
+        client.flush().await;
+        assert_eq!(received.lock().unwrap().len(), 0);
+
+        client.send_trace(Trace::new(Priority::AutoKeep)).unwrap();
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0].headers()["X-Datadog-Trace-Count"], "1");
+        assert_eq!(client.stats().sent, 1);
+    }
+
+    #[tokio::test]
+    async fn test_idle_client_sends_trace_within_flush_interval() {
+        tokio::time::pause();
+        let (client, connector) = recording_client(Config {
+            buffer_flush_max_interval: Duration::from_millis(300),
+            ..Default::default()
+        });
+        // idle past the ticks at 0 and 300 ms
+        tokio::time::delay_for(Duration::from_millis(350)).await;
+
+        client.send_trace(a_trace()).unwrap();
+        // the trace waits for the tick at 600 ms, not for a whole interval after it was sent
+        tokio::time::delay_for(Duration::from_millis(249)).await;
+        assert!(connector.requests().is_empty());
+
+        tokio::time::delay_for(Duration::from_millis(2)).await;
+        assert_eq!(connector.traces().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_flush_on_error() {
+        // the paused clock only reaches the next tick of the flush interval after 60 s, so only the error trace flushes
+        tokio::time::pause();
+        let (client, connector) = recording_client(Config {
+            buffer_flush_max_interval: Duration::from_secs(60),
+            flush_on_error: true,
+            ..Default::default()
+        });
+        // let the immediate first tick of the flush interval pass
+        tokio::time::delay_for(Duration::from_millis(1)).await;
+
+        client.send_trace(a_trace()).unwrap();
+        tokio::time::delay_for(Duration::from_millis(1)).await;
+        assert!(connector.requests().is_empty());
+
+        let mut trace = a_trace();
+        trace.spans[0].error = Some(ErrorInfo {
+            r#type: "timeout".to_string(),
+            msg: "query timed out".to_string(),
+            stack: String::new(),
+        });
+        client.send_trace(trace).unwrap();
+        tokio::time::delay_for(Duration::from_millis(1)).await;
+
+        assert_eq!(connector.requests().len(), 1);
+        let traces = connector.traces();
+        assert_eq!(traces.len(), 2);
+        assert_eq!(traces[1][0]["error"], 1);
+    }
+
+    #[tokio::test]
+    async fn test_retries() {
+        let (agent_url, received) = spawn_agent_with(|requests| match requests.len() {
+            1 | 2 => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::OK,
+        });
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(10),
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        assert_eq!(received.lock().unwrap().len(), 3);
+        assert_eq!(client.stats().sent, 1);
+        assert_eq!(client.stats().send_errors, 2);
+    }
+
+    #[tokio::test]
+    async fn test_retries_rate_limited() {
+        let clock = MockClock::new(SystemTime::now());
+        let connector = RecordingConnector::responding(|requests| match requests.len() {
+            1 => Some(RATE_LIMITED_RESPONSE),
+            _ => Some(OK_RESPONSE),
+        });
+        let client = Client::with_connector_and_clock(
+            Config {
+                max_retries: 1,
+                retry_backoff: Duration::from_millis(10),
+                retry_backoff_limit: Duration::from_millis(200),
+                buffer_flush_max_interval: Duration::from_secs(60),
+                ..Default::default()
+            },
+            connector.clone(),
+            Arc::new(clock.clone()),
+        )
+        .unwrap();
+
+        client.send_trace(a_trace()).unwrap();
+        let flush = tokio::spawn({
+            let client = client.clone();
+            async move { client.flush().await }
+        });
+        settle().await;
+        assert_eq!(connector.requests().len(), 1);
+
+        // the whole `Retry-After: 1` delay is waited, past the backoff limit
+        clock.advance(Duration::from_millis(999));
+        settle().await;
+        assert_eq!(connector.requests().len(), 1);
+        clock.advance(Duration::from_millis(1));
+        flush.await.unwrap();
+
+        assert_eq!(connector.requests().len(), 2);
+        let stats = client.stats();
+        assert_eq!((stats.sent, stats.send_errors), (1, 1));
+    }
+
+    const RATE_LIMITED_RESPONSE: &str =
+        "HTTP/1.1 429 Too Many Requests\r\nretry-after: 1\r\ncontent-length: 0\r\n\r\n";
+
+    #[test]
+    fn test_retry_after() {
+        let response = |retry_after: Option<&str>| {
+            let mut response = Response::new(Body::empty());
+            if let Some(retry_after) = retry_after {
+                response
+                    .headers_mut()
+                    .insert(RETRY_AFTER, retry_after.parse().unwrap());
+            }
+            response
+        };
+        assert_eq!(
+            retry_after(&response(Some("5"))),
+            Some(Duration::from_secs(5))
+        );
+        assert_eq!(retry_after(&response(None)), None);
+        assert_eq!(
+            retry_after(&response(Some("Wed, 21 Oct 2015 07:28:00 GMT"))),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn test_retries_exhausted() {
+        let (agent_url, received) = spawn_agent_with(|_| StatusCode::SERVICE_UNAVAILABLE);
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(10),
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        assert_eq!(received.lock().unwrap().len(), 3);
+        assert_eq!(client.stats().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_retries_transport_errors() {
+        let (agent_url, received) = spawn_flaky_agent().await;
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(10),
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        let stats = client.stats();
+        assert_eq!((stats.sent, stats.dropped, stats.send_errors), (1, 0, 1));
+    }
+
+    #[tokio::test]
+    async fn test_no_retries_on_client_errors() {
+        let (agent_url, received) = spawn_agent_with(|_| StatusCode::BAD_REQUEST);
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            max_retries: 2,
+            retry_backoff: Duration::from_millis(10),
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        assert_eq!(received.lock().unwrap().len(), 1);
+        assert_eq!(client.stats().dropped, 1);
+    }
+
+    #[tokio::test]
+    async fn test_failover_to_fallback_agent() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            // nothing listens on port 1
+            agent_url: Some("http://127.0.0.1:1".to_string()),
+            fallback_agent_urls: vec![agent_url],
+            max_retries: 0,
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        assert_eq!(received_trace_count(&received), 2);
+        let stats = client.stats();
+        assert_eq!(stats.sent, 2);
+        assert_eq!(stats.send_errors, 1);
+
+        assert!(matches!(
+            Client::try_new(Config {
+                fallback_agent_urls: vec!["unix:///var/run/datadog/apm.socket".to_string()],
+                ..Default::default()
+            }),
+            Err(ConfigError::InvalidAgentUrl(_))
+        ));
+        assert!(matches!(
+            Client::try_new(Config {
+                agent_url: Some("unix:///var/run/datadog/apm.socket".to_string()),
+                fallback_agent_urls: vec!["http://localhost:8126".to_string()],
+                ..Default::default()
+            }),
+            Err(ConfigError::UnixSocketFallback)
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_build_request_headers() {
+        let client = Client::new(Config {
+            compression: Compression::Gzip,
+            ..Default::default()
+        });
+        let endpoint = client.endpoint.lock().unwrap().clone();
+        for trace_count in &[1, 5, 200] {
+            let traces = (0..*trace_count).map(|_| a_trace()).collect::<Vec<_>>();
+            let raw_traces = client.map_to_raw_traces(&traces);
+            let payload = Bytes::from(
+                client
+                    .compression
+                    .compress(endpoint.version.serialize(&raw_traces).unwrap())
+                    .unwrap(),
+            );
+            let request = client
+                .build_request(&endpoint, payload.clone(), raw_traces.len())
+                .unwrap();
+
+            let headers = request.headers();
+            assert_eq!(
+                headers["X-Datadog-Trace-Count"],
+                trace_count.to_string().as_str()
+            );
+            assert_eq!(
+                headers["content-length"],
+                payload.len().to_string().as_str()
+            );
+            assert_eq!(headers["content-encoding"], "gzip");
+            let body = hyper::body::to_bytes(request.into_body()).await.unwrap();
+            assert_eq!(body, payload);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_downgrade() {
+        let (agent_url, received) =
+            spawn_agent_with(|requests| match requests.last().unwrap().uri().path() {
+                "/v0.5/traces" => StatusCode::NOT_FOUND,
+                _ => StatusCode::OK,
+            });
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        let paths = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|req| req.uri().path().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/v0.5/traces", "/v0.4/traces", "/v0.4/traces"]);
+    }
+
+    #[tokio::test]
+    async fn test_endpoint_url() {
+        let (agent_url, received) =
+            spawn_agent_with(|requests| match requests.last().unwrap().uri().path() {
+                "/dd-apm/traces" if requests.len() == 1 => StatusCode::OK,
+                _ => StatusCode::NOT_FOUND,
+            });
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            endpoint_url: Some(format!("{}/dd-apm/traces", agent_url)),
+            agent_url: Some("http://127.0.0.1:1".to_string()),
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+        // not downgraded when the endpoint answers `404`
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        let paths = received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|req| req.uri().path().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(paths, vec!["/dd-apm/traces", "/dd-apm/traces"]);
+        let stats = client.stats();
+        assert_eq!((stats.sent, stats.dropped), (1, 1));
+
+        for endpoint_url in &["/dd-apm/traces", "ftp://agent.internal/traces"] {
+            let config = Config {
+                endpoint_url: Some(endpoint_url.to_string()),
+                ..Default::default()
+            };
+            assert_eq!(
+                Client::try_new(config).unwrap_err(),
+                ConfigError::InvalidAgentUrl(endpoint_url.to_string())
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn test_request_timeout() {
+        let clock = MockClock::new(SystemTime::now());
+        // the agent never responds
+        let connector = RecordingConnector::responding(|_| None);
+        let client = Client::with_connector_and_clock(
+            Config {
+                request_timeout: Duration::from_millis(200),
+                max_retries: 0,
+                buffer_flush_max_interval: Duration::from_secs(60),
+                ..Default::default()
+            },
+            connector.clone(),
+            Arc::new(clock.clone()),
+        )
+        .unwrap();
+
+        client.send_trace(a_trace()).unwrap();
+        let flush = tokio::spawn({
+            let client = client.clone();
+            async move { client.flush().await }
+        });
+        settle().await;
+        assert_eq!(connector.requests().len(), 1);
+
+        clock.advance(Duration::from_millis(199));
+        settle().await;
+        assert_eq!(client.stats().send_errors, 0);
+        clock.advance(Duration::from_millis(1));
+        flush.await.unwrap();
+
+        let stats = client.stats();
+        assert_eq!(stats.send_errors, 1);
+        assert_eq!(stats.dropped, 1);
+        assert_eq!(stats.sent, 0);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_send_trace_over_unix_socket() {
+        let socket_path =
+            std::env::temp_dir().join(format!("datadog-apm-{}.socket", rand::random::<u64>()));
+        let received = spawn_unix_agent(&socket_path);
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            socket_path: Some(socket_path.clone()),
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.shutdown().await;
+        std::fs::remove_file(socket_path).unwrap();
+
+        assert_eq!(received_trace_count(&received), 1);
+    }
+
+    #[tokio::test]
+    async fn test_send_trace_with_recording_connector() {
+        let connector = RecordingConnector::default();
+        let client = Client::with_connector(
+            Config {
+                agent_url: Some("http://dd-agent.internal:8126".to_string()),
+                ..Default::default()
+            },
+            connector.clone(),
+        )
+        .unwrap();
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        let requests = connector.requests();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0]
+            .head
+            .starts_with("POST /v0.5/traces HTTP/1.1\r\n"));
+        assert!(requests[0]
+            .head
+            .contains("host: dd-agent.internal:8126\r\n"));
+        assert!(requests[0].head.contains("x-datadog-trace-count: 1\r\n"));
+        assert_eq!(client.stats().sent, 1);
+
+        let (client, connector) = recording_client(Config::default());
+        let trace = a_trace();
+        client.send_trace(trace.clone()).unwrap();
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        // the second request reuses the connection
+        assert_eq!(connector.requests().len(), 2);
+        let traces = connector.traces();
+        assert_eq!(traces.len(), 3);
+        assert_eq!(
+            u128::from(traces[0][0]["trace_id"].as_u64().unwrap()),
+            trace.id
+        );
+        assert_eq!(traces[0][0]["resource"], "/home/v3");
     }
-    if let Some(sql) = &span.sql {
-        meta.insert("sql.query".to_string(), sql.query.clone());
-        meta.insert("sql.rows".to_string(), sql.rows.clone());
-        meta.insert("sql.db".to_string(), sql.db.clone());
+
+    #[tokio::test]
+    async fn test_flush_interval_with_mock_clock() {
+        let clock = MockClock::new(SystemTime::now());
+        let (client, connector) = recording_client_with_clock(
+            Config {
+                buffer_flush_max_interval: Duration::from_secs(10),
+                ..Default::default()
+            },
+            Arc::new(clock.clone()),
+        );
+        // the consumer task starts waiting for the first flush
+        settle().await;
+
+        clock.advance(Duration::from_secs(1));
+        client.send_trace(a_trace()).unwrap();
+        settle().await;
+        clock.advance(Duration::from_millis(8_999));
+        settle().await;
+        assert!(connector.requests().is_empty());
+
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(connector.requests().len(), 1);
+        assert_eq!(client.stats().sent, 1);
+
+        // the next flush is a whole interval after the previous one
+        client.send_trace(a_trace()).unwrap();
+        clock.advance(Duration::from_millis(9_999));
+        settle().await;
+        assert_eq!(connector.requests().len(), 1);
+        clock.advance(Duration::from_millis(1));
+        settle().await;
+        assert_eq!(connector.requests().len(), 2);
     }
-    for (key, value) in &span.tags {
-        meta.insert(key.to_string(), value.to_string());
+
+    /// Lets the spawned tasks run until they all wait, like for the mock clock.
+    async fn settle() {
+        for _ in 0..100 {
+            let () = tokio::task::yield_now().await;
+        }
     }
-    meta
-}
 
-fn fill_metrics(priority: u32) -> HashMap<String, f64> {
-    let mut metrics = HashMap::new();
-    metrics.insert("_sampling_priority_v1".to_string(), f64::from(priority));
-    metrics
-}
+    #[test]
+    fn test_map_to_raw_spans_with_mock_clock() {
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let clock = MockClock::new(start);
+        let mut trace = a_trace();
+        let mut span = Span::builder("request", "GET /path").build_with(&clock);
+        clock.advance(Duration::from_micros(1_500));
+        span.finish_with(&clock);
+        trace.spans = vec![span];
 
-fn map_to_raw_spans(trace: &Trace, env: Option<String>, service: String) -> Vec<RawSpan> {
-    let mut traces = Vec::new();
-    for span in &trace.spans {
-        traces.push(RawSpan {
-            service: service.clone(),
-            trace_id: trace.id,
-            span_id: span.id,
-            name: span.name.clone(),
-            resource: span.resource.clone(),
-            parent_id: span.parent_id,
-            start: duration_to_nanos(span.start.duration_since(UNIX_EPOCH).unwrap()),
-            duration: duration_to_nanos(span.duration),
-            error: if span.error.is_some() { 1 } else { 0 },
-            r#type: span.r#type.clone(),
-            meta: fill_meta(&span, env.clone()),
-            metrics: fill_metrics(trace.priority),
-        });
+        let raw_spans = map_to_raw_spans(&trace, None, None, "", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].start, 1_600_000_000_000_000_000);
+        assert_eq!(raw_spans[0].duration, 1_500_000);
     }
-    traces
-}
 
-fn duration_to_nanos(duration: Duration) -> u64 {
-    duration.as_secs() * 1_000_000_000 + duration.subsec_nanos() as u64
-}
+    #[tokio::test]
+    async fn test_send_trace_with_connector() {
+        #[derive(Clone)]
+        struct CountingConnector(HttpConnector, Arc<AtomicUsize>);
 
-#[cfg(test)]
-mod tests {
-    extern crate rand;
+        impl Service<Uri> for CountingConnector {
+            type Response = <HttpConnector as Service<Uri>>::Response;
+            type Error = <HttpConnector as Service<Uri>>::Error;
+            type Future = <HttpConnector as Service<Uri>>::Future;
 
-    use super::*;
+            fn poll_ready(
+                &mut self,
+                cx: &mut std::task::Context<'_>,
+            ) -> std::task::Poll<Result<(), Self::Error>> {
+                self.0.poll_ready(cx)
+            }
 
-    use rand::Rng;
-    use serde_json::json;
+            fn call(&mut self, uri: Uri) -> Self::Future {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.call(uri)
+            }
+        }
 
-    #[tokio::test]
-    #[ignore]
-    async fn test_send_trace() {
+        let (agent_url, received) = spawn_agent();
+        let connections = Arc::new(AtomicUsize::new(0));
+        let connector = CountingConnector(HttpConnector::new(), connections.clone());
+        let client = Client::with_connector(
+            Config {
+                agent_url: Some(agent_url),
+                ..Default::default()
+            },
+            connector.clone(),
+        )
+        .unwrap();
+
+        client.send_trace(a_trace()).unwrap();
+        client.shutdown().await;
+
+        assert_eq!(received_trace_count(&received), 1);
+        assert_eq!(connections.load(Ordering::SeqCst), 1);
+
+        let config = || Config {
+            agent_url: Some("https://dd-agent.internal:8126".to_string()),
+            ..Default::default()
+        };
+        assert!(Client::with_connector(config(), connector.clone()).is_ok());
+        #[cfg(not(feature = "tls"))]
+        assert_eq!(
+            Client::try_new(config()).unwrap_err(),
+            ConfigError::InvalidAgentUrl("https://dd-agent.internal:8126".to_string())
+        );
+        #[cfg(feature = "tls")]
+        assert!(Client::try_new(config()).is_ok());
         let config = Config {
-            service: String::from("service_name"),
+            socket_path: Some(PathBuf::from("/var/run/datadog/apm.socket")),
             ..Default::default()
         };
-        let client = Client::new(config);
-        let trace = a_trace();
-        client.send_trace(trace);
+        assert_eq!(
+            Client::with_connector(config, connector).unwrap_err(),
+            ConfigError::UnixSocketConnector
+        );
+    }
+
+    #[test]
+    fn test_agent_address() {
+        let traces_endpoint = |agent_url: Option<&str>| {
+            let config = Config {
+                agent_url: agent_url.map(String::from),
+                ..Default::default()
+            };
+            AgentAddress::from_config(&config)
+                .map(|address| address.traces_endpoint(config.api_version).url())
+        };
+
+        assert_eq!(
+            traces_endpoint(None),
+            Ok("http://localhost:8126/v0.5/traces".to_string())
+        );
+        assert_eq!(
+            traces_endpoint(Some("http://dd-agent.internal:8126/")),
+            Ok("http://dd-agent.internal:8126/v0.5/traces".to_string())
+        );
+        assert_eq!(
+            traces_endpoint(Some("https://dd-agent.internal")),
+            Ok("https://dd-agent.internal/v0.5/traces".to_string())
+        );
+        assert_eq!(
+            AgentAddress::Tcp("http://localhost:8126".to_string())
+                .traces_endpoint(ApiVersion::V04)
+                .url(),
+            "http://localhost:8126/v0.4/traces"
+        );
+        for agent_url in &[
+            "dd-agent.internal:8126",
+            "ftp://dd-agent",
+            "http://",
+            "unix://",
+            "unix://apm.socket",
+        ] {
+            assert_eq!(
+                traces_endpoint(Some(agent_url)),
+                Err(ConfigError::InvalidAgentUrl(agent_url.to_string()))
+            );
+        }
+
+        assert_eq!(
+            AgentAddress::parse("unix:///var/run/datadog/apm.socket"),
+            Ok(AgentAddress::UnixSocket(PathBuf::from(
+                "/var/run/datadog/apm.socket"
+            )))
+        );
     }
 
     #[tokio::test]
@@ -307,46 +3172,776 @@ mod tests {
         let config = Config {
             service: String::from("service_name"),
             env: Some(String::from("staging")),
+            version: Some(String::from("1.2.3")),
             ..Default::default()
         };
         let trace = a_trace();
 
         let mut expected = Vec::new();
         for span in &trace.spans {
-            let mut meta: HashMap<String, String> = HashMap::new();
+            let mut meta = BTreeMap::new();
             meta.insert("env".to_string(), config.env.clone().unwrap());
+            meta.insert("version".to_string(), config.version.clone().unwrap());
             if let Some(http) = &span.http {
                 meta.insert("http.url".to_string(), http.url.clone());
                 meta.insert("http.method".to_string(), http.method.clone());
                 meta.insert("http.status_code".to_string(), http.status_code.clone());
             }
 
-            let mut metrics = HashMap::new();
+            let mut metrics = BTreeMap::new();
             metrics.insert(
                 "_sampling_priority_v1".to_string(),
-                f64::from(trace.priority),
+                f64::from(trace.priority.value()),
             );
 
             expected.push(RawSpan {
-                trace_id: trace.id,
+                trace_id: trace.id as u64,
                 span_id: span.id,
                 parent_id: span.parent_id,
                 name: span.name.clone(),
                 resource: span.resource.clone(),
                 service: config.service.clone(),
-                r#type: span.r#type.clone(),
+                r#type: span.r#type.to_string(),
                 start: duration_to_nanos(span.start.duration_since(UNIX_EPOCH).unwrap()),
                 duration: duration_to_nanos(span.duration),
                 error: 0,
-                meta: meta,
-                metrics: metrics,
+                meta,
+                metrics,
+                span_links: Vec::new(),
             });
         }
-        let raw_spans = map_to_raw_spans(&trace, config.env, config.service);
+        let raw_spans = map_to_raw_spans(
+            &trace,
+            config.env.as_deref(),
+            config.version.as_deref(),
+            &config.service,
+            &config.global_tags,
+            false,
+        );
 
         assert_eq!(raw_spans, expected);
     }
 
+    #[test]
+    fn test_map_to_raw_spans_with_global_tags() {
+        let mut global_tags = HashMap::new();
+        global_tags.insert("region".to_string(), "us-east-1".to_string());
+        global_tags.insert("team".to_string(), "platform".to_string());
+        let mut trace = a_trace();
+        trace.spans[0]
+            .tags
+            .insert("team".to_string(), "payments".to_string());
+
+        let raw_spans = map_to_raw_spans(&trace, None, None, "service_name", &global_tags, false);
+
+        let meta = &raw_spans[0].meta;
+        assert_eq!(meta["region"], "us-east-1");
+        assert_eq!(meta["team"], "payments");
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_with_messaging() {
+        let mut trace = a_trace();
+        trace.spans[0].messaging = Some(MessagingInfo {
+            system: "kafka".to_string(),
+            destination: "orders".to_string(),
+            operation: "publish".to_string(),
+        });
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+
+        let meta = &raw_spans[0].meta;
+        assert_eq!(meta["messaging.system"], "kafka");
+        assert_eq!(meta["messaging.destination"], "orders");
+        assert_eq!(meta["messaging.operation"], "publish");
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_with_cache() {
+        let mut trace = a_trace();
+        trace.spans[0].cache = Some(CacheInfo {
+            system: "redis".to_string(),
+            command: "GET user:1".to_string(),
+            key: "user:1".to_string(),
+        });
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        let meta = &raw_spans[0].meta;
+        assert_eq!(meta["cache.system"], "redis");
+        assert_eq!(meta["redis.raw_command"], "GET user:1");
+        assert_eq!(meta["cache.key"], "user:1");
+
+        trace.spans[0].cache = Some(CacheInfo {
+            system: "memcached".to_string(),
+            command: "flush_all".to_string(),
+            key: String::new(),
+        });
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        let meta = &raw_spans[0].meta;
+        assert_eq!(meta["cache.command"], "flush_all");
+        assert!(!meta.contains_key("redis.raw_command"));
+        assert!(!meta.contains_key("cache.key"));
+
+        trace.spans[0].cache.as_mut().unwrap().key = "k".repeat(500);
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].meta["cache.key"].len(), MAX_CACHE_KEY_LEN);
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_with_http_route() {
+        let mut trace = a_trace();
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert!(!raw_spans[0].meta.contains_key("http.route"));
+
+        trace.spans[0].http.as_mut().unwrap().route = Some("/home/v3/:id".to_string());
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].meta["http.route"], "/home/v3/:id");
+        assert_eq!(raw_spans[0].meta["http.url"], "/home/v3/2?trace=true");
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_with_http_sizes() {
+        let mut trace = a_trace();
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert!(!raw_spans[0]
+            .meta
+            .contains_key("http.request.content_length"));
+        assert!(!raw_spans[0]
+            .meta
+            .contains_key("http.response.content_length"));
+
+        let http = trace.spans[0].http.as_mut().unwrap();
+        http.request_size = Some(0);
+        http.response_size = Some(1_048_576);
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].meta["http.request.content_length"], "0");
+        assert_eq!(raw_spans[0].meta["http.response.content_length"], "1048576");
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_with_origin() {
+        let mut trace = a_trace();
+        trace.spans.push(
+            Span::builder("db", "SELECT")
+                .parent_id(trace.spans[0].id)
+                .build(),
+        );
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert!(raw_spans
+            .iter()
+            .all(|span| !span.meta.contains_key("_dd.origin")));
+
+        trace.origin = Some("synthetics".to_string());
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans.len(), 2);
+        assert!(raw_spans
+            .iter()
+            .all(|span| span.meta["_dd.origin"] == "synthetics"));
+    }
+
+    /// Sets environment variables, restoring their previous values when dropped.
+    struct EnvGuard {
+        saved: Vec<(&'static str, Option<String>)>,
+    }
+
+    impl EnvGuard {
+        fn set(vars: &[(&'static str, Option<&str>)]) -> EnvGuard {
+            let saved = vars
+                .iter()
+                .map(|(key, value)| {
+                    let saved = (*key, std::env::var(key).ok());
+                    match value {
+                        Some(value) => std::env::set_var(key, value),
+                        None => std::env::remove_var(key),
+                    }
+                    saved
+                })
+                .collect();
+            EnvGuard { saved }
+        }
+    }
+
+    impl Drop for EnvGuard {
+        fn drop(&mut self) {
+            for (key, value) in &self.saved {
+                match value {
+                    Some(value) => std::env::set_var(key, value),
+                    None => std::env::remove_var(key),
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_config_from_env() {
+        // a single test, so no other test changes these variables concurrently
+        {
+            let _guard = EnvGuard::set(&[
+                ("DD_AGENT_HOST", Some("dd-agent.internal")),
+                ("DD_TRACE_AGENT_PORT", Some("8127")),
+                ("DD_ENV", Some("staging")),
+                ("DD_SERVICE", Some("service_name")),
+                ("DD_VERSION", Some("1.2.3")),
+                ("DD_TAGS", Some("region:us-east-1")),
+                ("DD_TRACE_SAMPLE_RATE", Some("0.25")),
+            ]);
+            let config = Config::from_env();
+            assert_eq!(config.host, "dd-agent.internal");
+            assert_eq!(config.port, "8127");
+            assert_eq!(config.env, Some("staging".to_string()));
+            assert_eq!(config.service, "service_name");
+            assert_eq!(config.version, Some("1.2.3".to_string()));
+            assert_eq!(config.global_tags["region"], "us-east-1");
+            assert_eq!(config.sample_rate, 0.25);
+
+            let config = Config {
+                service: "overridden".to_string(),
+                ..Config::from_env()
+            };
+            assert_eq!(config.service, "overridden");
+            assert_eq!(config.env, Some("staging".to_string()));
+        }
+        {
+            let _guard = EnvGuard::set(&[
+                ("DD_AGENT_HOST", None),
+                ("DD_TRACE_AGENT_PORT", Some("")),
+                ("DD_ENV", None),
+                ("DD_SERVICE", None),
+                ("DD_VERSION", None),
+                ("DD_TAGS", None),
+                ("DD_TRACE_SAMPLE_RATE", Some("half")),
+            ]);
+            let config = Config::from_env();
+            let default = Config::default();
+            assert_eq!(config.host, default.host);
+            assert_eq!(config.port, default.port);
+            assert_eq!(config.env, None);
+            assert_eq!(config.service, default.service);
+            assert_eq!(config.version, None);
+            assert!(config.global_tags.is_empty());
+            assert_eq!(config.sample_rate, default.sample_rate);
+        }
+        for rate in &["2", "-0.5", "NaN"] {
+            let _guard = EnvGuard::set(&[("DD_TRACE_SAMPLE_RATE", Some(rate))]);
+            let config = Config::from_env();
+            assert_eq!(config.sample_rate, Config::default().sample_rate);
+            tokio::runtime::Runtime::new()
+                .unwrap()
+                .block_on(async { Client::new(config) });
+        }
+    }
+
+    #[test]
+    fn test_span_type() {
+        assert_eq!(SpanType::from("db"), SpanType::Db);
+        assert_eq!(SpanType::from("web").as_str(), "web");
+        assert_eq!(
+            SpanType::from("web ".to_string()),
+            SpanType::Custom("web ".to_string())
+        );
+        assert_eq!(SpanType::Custom("grpc".to_string()).to_string(), "grpc");
+    }
+
+    #[test]
+    fn test_parse_tags() {
+        let tags =
+            parse_tags("region:us-east-1, team:platform git.commit.sha:abc:123,invalid,:empty");
+
+        assert_eq!(tags.len(), 3);
+        assert_eq!(tags["region"], "us-east-1");
+        assert_eq!(tags["team"], "platform");
+        assert_eq!(tags["git.commit.sha"], "abc:123");
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_with_service_override() {
+        let mut trace = a_trace();
+        let mut database = trace.spans[0].clone();
+        database.id = trace.spans[0].id.wrapping_add(1);
+        database.parent_id = Some(trace.spans[0].id);
+        database.service = Some("postgres".to_string());
+        trace.spans.push(database);
+
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+
+        assert_eq!(raw_spans[0].service, "service_name");
+        assert_eq!(raw_spans[1].service, "postgres");
+    }
+
+    #[tokio::test]
+    async fn test_encode_trace() {
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            api_version: ApiVersion::V04,
+            ..Default::default()
+        });
+        let mut trace = a_trace();
+        for tag in &["a", "b", "c", "d"] {
+            trace.spans[0].tags.insert(tag.to_string(), tag.to_string());
+        }
+
+        let payload = client.encode_trace(&trace).unwrap();
+        assert_eq!(client.encode_trace(&trace.clone()).unwrap(), payload);
+
+        let traces: serde_json::Value = rmp_serde::from_read_ref(&payload).unwrap();
+        assert_eq!(traces[0][0]["span_id"], json!(trace.spans[0].id));
+        assert_eq!(traces[0][0]["meta"]["c"], json!("c"));
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_with_metrics() {
+        let mut trace = a_trace();
+        trace.spans[0].metrics.insert("db.rows".to_string(), 42.0);
+        trace.spans[0]
+            .metrics
+            .insert("_sampling_priority_v1".to_string(), -1.0);
+
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+
+        let metrics = &raw_spans[0].metrics;
+        assert_eq!(metrics["db.rows"], 42.0);
+        assert_eq!(
+            metrics["_sampling_priority_v1"],
+            f64::from(trace.priority.value())
+        );
+
+        // user rejected traces have a negative priority
+        trace.priority = Priority::UserReject;
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].metrics["_sampling_priority_v1"], -1.0);
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_priorities() {
+        let mut trace = a_trace();
+        for (priority, value) in &[
+            (Priority::UserReject, -1.0),
+            (Priority::AutoReject, 0.0),
+            (Priority::AutoKeep, 1.0),
+            (Priority::UserKeep, 2.0),
+        ] {
+            trace.priority = *priority;
+            let raw_spans =
+                map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+            assert_eq!(raw_spans[0].metrics["_sampling_priority_v1"], *value);
+        }
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_with_marked_error() {
+        let mut trace = a_trace();
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].error, 0);
+        assert!(!raw_spans[0].meta.contains_key("error.msg"));
+
+        trace.spans[0].mark_error("payment declined");
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].error, 1);
+        assert_eq!(raw_spans[0].meta["error.msg"], "payment declined");
+    }
+
+    #[test]
+    fn test_error_info_from_error() {
+        #[derive(Debug)]
+        struct QueryError(std::io::Error);
+
+        impl fmt::Display for QueryError {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                write!(f, "query failed")
+            }
+        }
+
+        impl std::error::Error for QueryError {
+            fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+                Some(&self.0)
+            }
+        }
+
+        let error = QueryError(std::io::Error::new(
+            std::io::ErrorKind::ConnectionReset,
+            "connection reset",
+        ));
+        let info = ErrorInfo::from_error(&error);
+        assert!(info.r#type.ends_with("QueryError"), "{}", info.r#type);
+        assert_eq!(info.msg, "query failed");
+        assert_eq!(info.stack, "caused by: connection reset");
+
+        let info = ErrorInfo::from_error(&std::fmt::Error);
+        assert!(info.r#type.ends_with("fmt::Error"), "{}", info.r#type);
+        assert_eq!(info.stack, "");
+    }
+
+    #[test]
+    fn test_obfuscate_sql_query() {
+        let cases = &[
+            (
+                "select * from users where email = 'a@b.com' and token = 'it''s'",
+                "select * from users where email = ? and token = ?",
+            ),
+            (
+                "select * from t1 where id = 42 and score > -1.5 and hex = 0x1F",
+                "select * from t1 where id = ? and score > -? and hex = ?",
+            ),
+            (
+                "select * from users where id in (1, 2, 3) and name in ('a','b')",
+                "select * from users where id in (?) and name in (?)",
+            ),
+            (
+                "select \"col 1\" from users where id = $1 limit 10",
+                "select \"col 1\" from users where id = $1 limit ?",
+            ),
+            ("select 'unterminated", "select ?"),
+        ];
+        for (query, expected) in cases {
+            assert_eq!(obfuscate_sql_query(query), *expected);
+        }
+
+        let mut trace = a_trace();
+        trace.spans[0].sql = Some(SqlInfo {
+            query: "select * from users where id = 42".to_string(),
+            rows: "1".to_string(),
+            db: "test".to_string(),
+        });
+        let raw_spans = map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), true);
+        assert_eq!(
+            raw_spans[0].meta["sql.query"],
+            "select * from users where id = ?"
+        );
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(
+            raw_spans[0].meta["sql.query"],
+            "select * from users where id = 42"
+        );
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_128_bit_trace_id() {
+        let mut trace = a_trace();
+        trace.id = 0x640cfd8d_00000000_0000000f_00000001;
+        trace.spans[0].links = vec![SpanLink {
+            trace_id: 0x2_00000000_00000003,
+            span_id: 4,
+            ..Default::default()
+        }];
+
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].trace_id, 0x0000000f_00000001);
+        assert_eq!(raw_spans[0].meta["_dd.p.tid"], "640cfd8d00000000");
+        assert_eq!(raw_spans[0].span_links[0].trace_id, 3);
+        assert_eq!(raw_spans[0].span_links[0].trace_id_high, 2);
+        assert_eq!(
+            span_links_json(&raw_spans[0].span_links),
+            r#"[{"trace_id":"00000000000000020000000000000003","span_id":"0000000000000004"}]"#
+        );
+
+        trace.id = 1;
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert!(!raw_spans[0].meta.contains_key("_dd.p.tid"));
+    }
+
+    #[test]
+    fn test_truncate_tag_value() {
+        let mut value = "a".repeat(30);
+        truncate_tag_value(&mut value, 30);
+        assert_eq!(value, "a".repeat(30));
+
+        truncate_tag_value(&mut value, 20);
+        assert_eq!(value, "aaaaaa...[truncated]");
+
+        // not splitting a multi-byte character
+        let mut value = "é".repeat(20);
+        truncate_tag_value(&mut value, 21);
+        assert_eq!(value, "ééé...[truncated]");
+
+        let mut value = "a".repeat(30);
+        truncate_tag_value(&mut value, 5);
+        assert_eq!(value, "");
+    }
+
+    #[tokio::test]
+    async fn test_encode_trace_with_hostname() {
+        let client = Client::new(Config {
+            api_version: ApiVersion::V04,
+            hostname: Some("web-1".to_string()),
+            ..Default::default()
+        });
+        let payload = client.encode_trace(&a_trace()).unwrap();
+        let traces: serde_json::Value = rmp_serde::from_read_ref(&payload).unwrap();
+        assert_eq!(traces[0][0]["meta"]["_dd.hostname"], "web-1");
+
+        let client = Client::new(Config {
+            api_version: ApiVersion::V04,
+            ..Default::default()
+        });
+        let payload = client.encode_trace(&a_trace()).unwrap();
+        let traces: serde_json::Value = rmp_serde::from_read_ref(&payload).unwrap();
+        assert!(traces[0][0]["meta"].get("_dd.hostname").is_none());
+    }
+
+    #[tokio::test]
+    async fn test_encode_trace_with_analytics_sample_rate() {
+        let analytics_rates = |analytics_sample_rate: Option<f64>| {
+            let client = Client::new(Config {
+                api_version: ApiVersion::V04,
+                analytics_sample_rate,
+                ..Default::default()
+            });
+            let mut trace = a_trace();
+            trace.spans.push(
+                Span::builder("query", "SELECT")
+                    .parent_id(trace.spans[0].id)
+                    .analytics_rate(0.75)
+                    .build(),
+            );
+            let payload = client.encode_trace(&trace).unwrap();
+            let traces: serde_json::Value = rmp_serde::from_read_ref(&payload).unwrap();
+            traces[0]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|span| span["metrics"]["_dd1.sr.eausr"].as_f64())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(analytics_rates(None), vec![None, Some(0.75)]);
+        // the span rate takes precedence over the global one
+        assert_eq!(analytics_rates(Some(0.25)), vec![Some(0.25), Some(0.75)]);
+        assert_eq!(analytics_rates(Some(1.5)), vec![Some(1.0), Some(0.75)]);
+        assert_eq!(analytics_rates(Some(-1.0)), vec![Some(0.0), Some(0.75)]);
+    }
+
+    #[tokio::test]
+    async fn test_encode_trace_truncates_long_meta() {
+        let client = Client::new(Config {
+            api_version: ApiVersion::V04,
+            max_tag_value_len: 100,
+            ..Default::default()
+        });
+        let mut trace = a_trace();
+        trace.spans[0].error = Some(ErrorInfo {
+            r#type: "panic".to_string(),
+            msg: "boom".to_string(),
+            stack: "frame\n".repeat(1000),
+        });
+
+        let payload = client.encode_trace(&trace).unwrap();
+        let traces: serde_json::Value = rmp_serde::from_read_ref(&payload).unwrap();
+        let stack = traces[0][0]["meta"]["error.stack"].as_str().unwrap();
+        assert_eq!(stack.len(), 100);
+        assert!(stack.ends_with("...[truncated]"));
+        assert_eq!(traces[0][0]["meta"]["error.msg"], "boom");
+    }
+
+    #[tokio::test]
+    async fn test_encode_trace_truncates_long_meta_by_default() {
+        let client = Client::new(Config::default());
+        let mut trace = a_trace();
+        trace.spans[0].sql = Some(SqlInfo {
+            query: format!("select * from users where id in ({})", "1, ".repeat(10_000)),
+            rows: "1".to_string(),
+            db: "test".to_string(),
+        });
+
+        // v0.5 payloads hold the meta values in the string table
+        let payload = client.encode_trace(&trace).unwrap();
+        let (strings, _): (Vec<String>, serde::de::IgnoredAny) =
+            rmp_serde::from_read_ref(&payload).unwrap();
+        let query = strings
+            .iter()
+            .find(|string| string.starts_with("select"))
+            .unwrap();
+        assert_eq!(query.len(), 25_000);
+        assert!(query.ends_with("...[truncated]"));
+    }
+
+    #[test]
+    fn test_span_links() {
+        let mut trace = a_trace();
+        let mut attributes = HashMap::new();
+        attributes.insert("message".to_string(), "say \"hi\"".to_string());
+        trace.spans[0].links = vec![
+            SpanLink {
+                trace_id: 1,
+                span_id: 255,
+                attributes,
+            },
+            SpanLink {
+                trace_id: 2,
+                span_id: 3,
+                ..Default::default()
+            },
+        ];
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+
+        let result = serialize_as_msgpack(std::slice::from_ref(&raw_spans)).unwrap();
+        let traces: serde_json::Value = rmp_serde::from_read_ref(&result).unwrap();
+        assert_eq!(
+            traces[0][0]["span_links"],
+            json!([
+                {"trace_id": 1, "span_id": 255, "attributes": {"message": "say \"hi\""}},
+                {"trace_id": 2, "span_id": 3},
+            ])
+        );
+
+        let result = serialize_as_msgpack_v05(&[raw_spans]).unwrap();
+        let (strings, _): (Vec<String>, Vec<serde::de::IgnoredAny>) =
+            rmp_serde::from_read_ref(&result).unwrap();
+        let links = strings.iter().find(|s| s.starts_with('[')).unwrap();
+        let links: serde_json::Value = serde_json::from_str(links).unwrap();
+        assert_eq!(
+            links,
+            json!([
+                {
+                    "trace_id": "00000000000000000000000000000001",
+                    "span_id": "00000000000000ff",
+                    "attributes": {"message": "say \"hi\""},
+                },
+                {"trace_id": "00000000000000000000000000000002", "span_id": "0000000000000003"},
+            ])
+        );
+        assert!(strings.iter().any(|s| s == "_dd.span_links"));
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_measured() {
+        let mut trace = a_trace();
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert!(!raw_spans[0].metrics.contains_key("_dd.measured"));
+
+        trace.spans[0].measured = true;
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].metrics["_dd.measured"], 1.0);
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_analytics_rate() {
+        let mut trace = a_trace();
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert!(!raw_spans[0].metrics.contains_key("_dd1.sr.eausr"));
+
+        trace.spans[0].analytics_rate = Some(0.5);
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].metrics["_dd1.sr.eausr"], 0.5);
+
+        trace.spans[0].analytics_rate = Some(2.0);
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+        assert_eq!(raw_spans[0].metrics["_dd1.sr.eausr"], 1.0);
+    }
+
+    #[test]
+    fn test_map_to_raw_spans_before_unix_epoch() {
+        let mut trace = a_trace();
+        trace.spans[0].start = UNIX_EPOCH - Duration::from_secs(60);
+
+        let raw_spans =
+            map_to_raw_spans(&trace, None, None, "service_name", &HashMap::new(), false);
+
+        assert_eq!(raw_spans[0].start, 0);
+        assert_eq!(
+            raw_spans[0].duration,
+            duration_to_nanos(trace.spans[0].duration)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_gzip_compression() {
+        let (agent_url, received) = spawn_agent();
+        let client = Client::new(Config {
+            service: String::from("service_name"),
+            agent_url: Some(agent_url),
+            compression: Compression::Gzip,
+            ..Default::default()
+        });
+
+        client.send_trace(a_trace()).unwrap();
+        client.flush().await;
+
+        let received = received.lock().unwrap();
+        assert_eq!(received[0].headers()["content-encoding"], "gzip");
+
+        let mut decoder = flate2::read::GzDecoder::new(&received[0].body()[..]);
+        let mut payload = Vec::new();
+        decoder.read_to_end(&mut payload).unwrap();
+        let (_, traces): (Vec<String>, Vec<serde::de::IgnoredAny>) =
+            rmp_serde::from_read_ref(&payload).unwrap();
+        assert_eq!(traces.len(), 1);
+    }
+
+    #[test]
+    fn test_compression_round_trip() {
+        let raw_spans = map_to_raw_spans(
+            &a_trace(),
+            None,
+            None,
+            "service_name",
+            &HashMap::new(),
+            false,
+        );
+        let payload = serialize_as_msgpack(&[raw_spans]).unwrap();
+
+        assert_eq!(
+            Compression::None.compress(payload.clone()).unwrap(),
+            payload
+        );
+
+        let compressed = Compression::Gzip.compress(payload.clone()).unwrap();
+        let mut decoder = flate2::read::GzDecoder::new(&compressed[..]);
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, payload);
+
+        let compressed = Compression::Deflate.compress(payload.clone()).unwrap();
+        let mut decoder = flate2::read::ZlibDecoder::new(&compressed[..]);
+        let mut decompressed = Vec::new();
+        decoder.read_to_end(&mut decompressed).unwrap();
+        assert_eq!(decompressed, payload);
+    }
+
+    #[tokio::test]
+    async fn test_degenerate_input_does_not_panic() {
+        let mut span =
+            map_to_raw_spans(&a_trace(), None, None, "", &HashMap::new(), false).remove(0);
+        span.name = String::new();
+        span.metrics.insert("ratio".to_string(), f64::NAN);
+        for version in &[ApiVersion::V05, ApiVersion::V04] {
+            assert!(version.serialize(&[]).is_ok());
+            assert!(version.serialize(&[vec![], vec![span.clone()]]).is_ok());
+        }
+
+        let client = Client::new(Config::default());
+        let endpoint = Endpoint {
+            base_url: "http://not a host".to_string(),
+            version: ApiVersion::V05,
+            verbatim: false,
+        };
+        assert_eq!(
+            client.do_send_batch(&endpoint, Bytes::new(), 0).await,
+            SendOutcome::Fail
+        );
+    }
+
     #[tokio::test]
     async fn test_message_pack_serialization() {
         let generate_span = || {
@@ -366,13 +3961,14 @@ mod tests {
                 start: now * 1_000_000_000,
                 duration: 4853472865,
                 error: 0,
-                meta: std::collections::HashMap::new(),
-                metrics: std::collections::HashMap::new(),
+                meta: BTreeMap::new(),
+                metrics: BTreeMap::new(),
+                span_links: Vec::new(),
             }
         };
 
         let traces = (0..3).map(|_| vec![generate_span()]).collect::<Vec<_>>();
-        let result = serialize_as_msgpack(traces.clone());
+        let result = serialize_as_msgpack(&traces).unwrap();
 
         let msgpack_as_json: serde_json::Value = rmp_serde::from_read_ref(&result).unwrap();
 
@@ -382,16 +3978,187 @@ mod tests {
         assert_eq!(msgpack_as_json, json!(traces));
     }
 
+    type Received = Arc<Mutex<Vec<Request<Bytes>>>>;
+    type Respond = fn(&[Request<Bytes>]) -> StatusCode;
+
+    /// Spawns a fake datadog agent, returning its url and the requests it received.
+    fn spawn_agent() -> (String, Received) {
+        spawn_agent_with(|_| StatusCode::OK)
+    }
+
+    /// Spawns a fake datadog agent answering with the status returned by `respond`,
+    /// that is called with all the requests received so far, the last one being the current.
+    fn spawn_agent_with(respond: Respond) -> (String, Received) {
+        let received = Received::default();
+        let requests = received.clone();
+        let make_service = make_service_fn(move |_| {
+            let requests = requests.clone();
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |req| {
+                    record_request(requests.clone(), respond, req)
+                }))
+            }
+        });
+        let server = Server::bind(&([127, 0, 0, 1], 0).into()).serve(make_service);
+        let agent_url = format!("http://{}", server.local_addr());
+        tokio::spawn(server);
+        (agent_url, received)
+    }
+
+    /// Spawns a fake datadog agent that closes its first connection without responding,
+    /// and answers the next requests like `spawn_agent`.
+    async fn spawn_flaky_agent() -> (String, Received) {
+        let received = Received::default();
+        let requests = received.clone();
+        let mut listener =
+            tokio::net::TcpListener::bind(std::net::SocketAddr::from(([127, 0, 0, 1], 0)))
+                .await
+                .unwrap();
+        let agent_url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let mut accepted = 0;
+            while let Ok((stream, _)) = listener.accept().await {
+                accepted += 1;
+                if accepted == 1 {
+                    drop(stream);
+                    continue;
+                }
+                let requests = requests.clone();
+                let service = service_fn(move |req| {
+                    record_request(requests.clone(), |_| StatusCode::OK, req)
+                });
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        });
+        (agent_url, received)
+    }
+
+    /// Spawns a fake datadog agent listening on a unix domain socket, returning the requests it received.
+    #[cfg(unix)]
+    fn spawn_unix_agent(socket_path: &std::path::Path) -> Received {
+        let received = Received::default();
+        let requests = received.clone();
+        let mut listener = tokio::net::UnixListener::bind(socket_path).unwrap();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let requests = requests.clone();
+                let service = service_fn(move |req| {
+                    record_request(requests.clone(), |_| StatusCode::OK, req)
+                });
+                tokio::spawn(Http::new().serve_connection(stream, service));
+            }
+        });
+        received
+    }
+
+    async fn record_request(
+        requests: Received,
+        respond: Respond,
+        req: Request<Body>,
+    ) -> Result<Response<Body>, hyper::Error> {
+        let (parts, body) = req.into_parts();
+        let body = hyper::body::to_bytes(body).await?;
+        let mut requests = requests.lock().unwrap();
+        requests.push(Request::from_parts(parts, body));
+        // like the datadog agent, answering with the sampling rates
+        let mut response = Response::new(Body::from(AGENT_RESPONSE));
+        *response.status_mut() = respond(&requests);
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            response
+                .headers_mut()
+                .insert(RETRY_AFTER, "1".parse().unwrap());
+        }
+        Ok(response)
+    }
+
+    const AGENT_RESPONSE: &str =
+        r#"{"rate_by_service":{"service:,env:":1,"service:service_name,env:staging":0.25}}"#;
+
+    fn received_trace_count(received: &Received) -> usize {
+        received
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|req| {
+                req.headers()["X-Datadog-Trace-Count"]
+                    .to_str()
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap()
+            })
+            .sum()
+    }
+
+    #[test]
+    fn test_message_pack_serialization_v05() {
+        let trace = a_trace();
+        let raw_spans = map_to_raw_spans(
+            &trace,
+            Some("staging"),
+            None,
+            "service_name",
+            &HashMap::new(),
+            false,
+        );
+        let result = serialize_as_msgpack_v05(&[raw_spans.clone(), raw_spans]).unwrap();
+
+        type SpanV05 = (
+            u32,
+            u32,
+            u32,
+            u64,
+            u64,
+            u64,
+            i64,
+            i64,
+            i32,
+            HashMap<u32, u32>,
+            HashMap<u32, f64>,
+            u32,
+        );
+        let (strings, traces): (Vec<String>, Vec<Vec<SpanV05>>) =
+            rmp_serde::from_read_ref(&result).unwrap();
+        let string = |index: u32| strings[index as usize].as_str();
+
+        assert_eq!(strings[0], "");
+        assert_eq!(strings.iter().filter(|s| *s == "request").count(), 1);
+        assert_eq!(traces.len(), 2);
+        let span = &traces[1][0];
+        assert_eq!(string(span.0), "service_name");
+        assert_eq!(string(span.1), "request");
+        assert_eq!(string(span.2), "/home/v3");
+        assert_eq!(u128::from(span.3), trace.id);
+        assert_eq!(span.4, trace.spans[0].id);
+        assert_eq!(span.5, 0);
+        assert_eq!(span.7, 2_000_000_000);
+        assert_eq!(span.8, 0);
+        let meta = span
+            .9
+            .iter()
+            .map(|(key, value)| (string(*key), string(*value)))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(meta["env"], "staging");
+        assert_eq!(meta["http.method"], "GET");
+        let metrics = span
+            .10
+            .iter()
+            .map(|(key, value)| (string(*key), *value))
+            .collect::<HashMap<_, _>>();
+        assert_eq!(metrics["_sampling_priority_v1"], 1.0);
+        assert_eq!(string(span.11), "web");
+    }
+
     fn a_trace() -> Trace {
         let mut rng = rand::thread_rng();
         Trace {
-            id: rng.gen::<u64>(),
-            priority: 1,
+            id: u128::from(rng.gen::<u64>()),
+            priority: Priority::AutoKeep,
+            origin: None,
             spans: vec![Span {
                 id: rng.gen::<u64>(),
                 name: String::from("request"),
                 resource: String::from("/home/v3"),
-                r#type: String::from("web"),
+                r#type: SpanType::Web,
                 start: SystemTime::now(),
                 duration: Duration::from_secs(2),
                 parent_id: None,
@@ -399,10 +4166,20 @@ mod tests {
                     url: String::from("/home/v3/2?trace=true"),
                     method: String::from("GET"),
                     status_code: String::from("200"),
+                    request_size: None,
+                    response_size: None,
+                    route: None,
                 }),
                 error: None,
                 sql: None,
+                messaging: None,
+                cache: None,
                 tags: HashMap::new(),
+                metrics: HashMap::new(),
+                service: None,
+                measured: false,
+                analytics_rate: None,
+                links: Vec::new(),
             }],
         }
     }
@@ -0,0 +1,125 @@
+//! Source of the current time of the client and of the spans it times, so their timing can be tested with a mock clock.
+
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::time::{Duration, SystemTime};
+
+#[cfg(test)]
+use std::sync::{Arc, Mutex};
+#[cfg(test)]
+use tokio::sync::oneshot;
+
+/// Future resolving once the duration given to [`Clock::sleep`](trait.Clock.html#tymethod.sleep) elapsed.
+pub(crate) type Sleep = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+pub(crate) trait Clock: fmt::Debug + Send + Sync {
+    /// The current time, like the start and the end of the spans.
+    fn now(&self) -> SystemTime;
+
+    /// Waits for `duration` on this clock, like the buffer flush interval.
+    fn sleep(&self, duration: Duration) -> Sleep;
+}
+
+/// The system clock, with the sleeps of the tokio timer.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        Box::pin(tokio::time::delay_for(duration))
+    }
+}
+
+/// A clock that only moves forward when the test advances it.
+#[cfg(test)]
+#[derive(Debug, Clone)]
+pub(crate) struct MockClock(Arc<Mutex<MockClockState>>);
+
+#[cfg(test)]
+#[derive(Debug)]
+struct MockClockState {
+    now: SystemTime,
+    /// The deadlines of the pending sleeps, woken by `advance`.
+    sleeps: Vec<(SystemTime, oneshot::Sender<()>)>,
+}
+
+#[cfg(test)]
+impl MockClock {
+    pub(crate) fn new(now: SystemTime) -> MockClock {
+        MockClock(Arc::new(Mutex::new(MockClockState {
+            now,
+            sleeps: Vec::new(),
+        })))
+    }
+
+    /// Moves the clock forward, waking the sleeps whose deadline is reached.
+    pub(crate) fn advance(&self, duration: Duration) {
+        let mut state = self.0.lock().unwrap();
+        state.now += duration;
+        let now = state.now;
+        let (elapsed, pending) = state
+            .sleeps
+            .drain(..)
+            .partition(|(deadline, _)| *deadline <= now);
+        state.sleeps = pending;
+        for (_, sleep) in elapsed {
+            let _ = sleep.send(());
+        }
+    }
+}
+
+#[cfg(test)]
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        self.0.lock().unwrap().now
+    }
+
+    fn sleep(&self, duration: Duration) -> Sleep {
+        if duration == Duration::from_secs(0) {
+            return Box::pin(async {});
+        }
+        let (sender, receiver) = oneshot::channel();
+        let mut state = self.0.lock().unwrap();
+        let deadline = state.now + duration;
+        state.sleeps.push((deadline, sender));
+        Box::pin(async move {
+            let _ = receiver.await;
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::client::Span;
+
+    use std::time::UNIX_EPOCH;
+
+    #[tokio::test]
+    async fn test_mock_clock() {
+        // the tokio timeouts only check whether the sleep is pending, the paused tokio clock runs them instantly
+        tokio::time::pause();
+        let start = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+        let clock = MockClock::new(start);
+        let timeout = Duration::from_secs(1);
+
+        let mut sleep = clock.sleep(Duration::from_secs(10));
+        clock.advance(Duration::from_secs(9));
+        assert_eq!(clock.now(), start + Duration::from_secs(9));
+        assert!(tokio::time::timeout(timeout, &mut sleep).await.is_err());
+        clock.advance(Duration::from_secs(1));
+        assert!(tokio::time::timeout(timeout, &mut sleep).await.is_ok());
+
+        let mut span = Span::builder("request", "GET /path").build_with(&clock);
+        clock.advance(Duration::from_millis(250));
+        span.finish_with(&clock);
+        assert_eq!(span.start, start + Duration::from_secs(10));
+        assert_eq!(span.duration, Duration::from_millis(250));
+    }
+}
@@ -0,0 +1,166 @@
+use hyper::client::connect::{Connected, Connection, HttpConnector};
+use hyper::service::Service;
+use hyper::Uri;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+#[cfg(unix)]
+use tokio::net::UnixStream;
+
+use std::error::Error;
+use std::fmt;
+use std::future::Future;
+use std::io;
+#[cfg(unix)]
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+
+/// Connects to the datadog agent, either over tcp, over a unix domain socket or with a connector given by the user.
+///
+/// When connecting over a unix domain socket the host of the request uri is ignored.
+#[derive(Debug, Clone)]
+pub(crate) enum AgentConnector {
+    Tcp(HttpConnector),
+    #[cfg(unix)]
+    Unix(PathBuf),
+    Custom(CustomConnector),
+}
+
+pub(crate) enum AgentStream {
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+    Custom(Box<dyn CustomStream>),
+}
+
+type ConnectFuture =
+    Pin<Box<dyn Future<Output = Result<AgentStream, Box<dyn Error + Send + Sync>>> + Send>>;
+
+/// Connection of a [`CustomConnector`](struct.CustomConnector.html).
+pub(crate) trait CustomStream: AsyncRead + AsyncWrite + Connection + Send + Unpin {}
+
+impl<T: AsyncRead + AsyncWrite + Connection + Send + Unpin> CustomStream for T {}
+
+/// A connector given by the user, like a tls or proxy connector, with its type erased.
+#[derive(Clone)]
+pub(crate) struct CustomConnector(Arc<dyn Fn(Uri) -> ConnectFuture + Send + Sync>);
+
+impl CustomConnector {
+    pub(crate) fn new<C>(connector: C) -> CustomConnector
+    where
+        C: Service<Uri> + Clone + Send + Sync + 'static,
+        C::Response: AsyncRead + AsyncWrite + Connection + Send + Unpin + 'static,
+        C::Error: Into<Box<dyn Error + Send + Sync>>,
+        C::Future: Send + 'static,
+    {
+        CustomConnector(Arc::new(move |uri| {
+            let mut connector = connector.clone();
+            Box::pin(async move {
+                std::future::poll_fn(|cx| connector.poll_ready(cx))
+                    .await
+                    .map_err(Into::into)?;
+                let stream = connector.call(uri).await.map_err(Into::into)?;
+                Ok(AgentStream::Custom(Box::new(stream)))
+            })
+        }))
+    }
+}
+
+impl fmt::Debug for CustomConnector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CustomConnector")
+    }
+}
+
+impl Service<Uri> for AgentConnector {
+    type Response = AgentStream;
+    type Error = Box<dyn Error + Send + Sync>;
+    type Future = ConnectFuture;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        match self {
+            AgentConnector::Tcp(connector) => connector.poll_ready(cx).map_err(Into::into),
+            #[cfg(unix)]
+            AgentConnector::Unix(_) => Poll::Ready(Ok(())),
+            // the custom connector is cloned and polled until ready on every call
+            AgentConnector::Custom(_) => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        match self {
+            AgentConnector::Tcp(connector) => {
+                let connecting = connector.call(uri);
+                Box::pin(async move { Ok(AgentStream::Tcp(connecting.await?)) })
+            }
+            #[cfg(unix)]
+            AgentConnector::Unix(socket_path) => {
+                let socket_path = socket_path.clone();
+                Box::pin(
+                    async move { Ok(AgentStream::Unix(UnixStream::connect(socket_path).await?)) },
+                )
+            }
+            AgentConnector::Custom(connector) => (connector.0)(uri),
+        }
+    }
+}
+
+impl Connection for AgentStream {
+    fn connected(&self) -> Connected {
+        match self {
+            AgentStream::Tcp(stream) => stream.connected(),
+            #[cfg(unix)]
+            AgentStream::Unix(_) => Connected::new(),
+            AgentStream::Custom(stream) => stream.connected(),
+        }
+    }
+}
+
+impl AsyncRead for AgentStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AgentStream::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            #[cfg(unix)]
+            AgentStream::Unix(stream) => Pin::new(stream).poll_read(cx, buf),
+            AgentStream::Custom(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for AgentStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            AgentStream::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            #[cfg(unix)]
+            AgentStream::Unix(stream) => Pin::new(stream).poll_write(cx, buf),
+            AgentStream::Custom(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AgentStream::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            #[cfg(unix)]
+            AgentStream::Unix(stream) => Pin::new(stream).poll_flush(cx),
+            AgentStream::Custom(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            AgentStream::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            #[cfg(unix)]
+            AgentStream::Unix(stream) => Pin::new(stream).poll_shutdown(cx),
+            AgentStream::Custom(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
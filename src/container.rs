@@ -0,0 +1,83 @@
+/// Returns the id of the container the process runs in, read from `/proc/self/cgroup`.
+///
+/// Returns `None` outside a container, on platforms other than linux, or when the file can not be read.
+pub(crate) fn container_id() -> Option<String> {
+    if cfg!(target_os = "linux") {
+        let cgroup = std::fs::read_to_string("/proc/self/cgroup").ok()?;
+        parse_container_id(&cgroup)
+    } else {
+        None
+    }
+}
+
+/// Finds the container id in the `hierarchy:controllers:path` lines of a cgroup file,
+/// at the end of a path like `/docker/<id>` or `/kubepods.slice/.../cri-containerd-<id>.scope`.
+fn parse_container_id(cgroup: &str) -> Option<String> {
+    cgroup.lines().find_map(|line| {
+        let path = line.splitn(3, ':').nth(2)?;
+        let name = path.rsplit('/').next()?;
+        let name = name.strip_suffix(".scope").unwrap_or(name);
+        // the id may be prefixed by the runtime, like `docker-<id>`, and an uuid contains dashes itself
+        std::iter::once(name)
+            .chain(name.match_indices('-').map(|(index, _)| &name[index + 1..]))
+            .find(|candidate| is_container_id(candidate))
+            .map(String::from)
+    })
+}
+
+/// Checks for the 64 hexadecimal characters id of docker and containerd, an uuid,
+/// or the `<32 hexadecimal characters>-<number>` id of an aws ecs task.
+fn is_container_id(id: &str) -> bool {
+    let is_hex =
+        |part: &str, len: usize| part.len() == len && part.chars().all(|c| c.is_ascii_hexdigit());
+    let parts = id.split('-').collect::<Vec<_>>();
+    match parts.as_slice() {
+        [id] => is_hex(id, 64),
+        [a, b, c, d, e] => {
+            is_hex(a, 8) && is_hex(b, 4) && is_hex(c, 4) && is_hex(d, 4) && is_hex(e, 12)
+        }
+        [task, number] => {
+            is_hex(task, 32) && !number.is_empty() && number.chars().all(|c| c.is_ascii_digit())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ID: &str = "3726184226f5d3147c25fdeab5b60097e378e8a720503a5e19ecfdf29f869860";
+
+    #[test]
+    fn test_parse_container_id() {
+        let docker = format!("13:name=systemd:/docker/{}\n12:pids:/docker/{}\n", ID, ID);
+        assert_eq!(parse_container_id(&docker), Some(ID.to_string()));
+
+        let kubernetes = format!(
+            "0::/kubepods.slice/kubepods-burstable.slice/kubepods-burstable-pod2d3da189_6407_48e3_9ab6_78188d75e609.slice/cri-containerd-{}.scope\n",
+            ID
+        );
+        assert_eq!(parse_container_id(&kubernetes), Some(ID.to_string()));
+
+        let uuid = "1:name=systemd:/uuid/34dc0b5e-626f-2c5c-4c51-70e34b10e765\n";
+        assert_eq!(
+            parse_container_id(uuid),
+            Some("34dc0b5e-626f-2c5c-4c51-70e34b10e765".to_string())
+        );
+
+        let ecs = "1:cpu:/ecs/34dc0b5e626f2c5c4c5170e34b10e765/34dc0b5e626f2c5c4c5170e34b10e765-1234567890\n";
+        assert_eq!(
+            parse_container_id(ecs),
+            Some("34dc0b5e626f2c5c4c5170e34b10e765-1234567890".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_container_id_outside_a_container() {
+        let host = "9:name=systemd:/\n4:memory:/user.slice\n0::/init.scope\n";
+        assert_eq!(parse_container_id(host), None);
+        assert_eq!(parse_container_id(""), None);
+        assert_eq!(parse_container_id("not a cgroup file"), None);
+    }
+}
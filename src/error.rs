@@ -0,0 +1,76 @@
+use crate::client::Trace;
+use std::fmt;
+
+/// Errors returned when a [`Config`](struct.Config.html) can not be turned into a client.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// The `agent_url` is not a valid `http://host[:port]` or `unix:///path` url,
+    /// a fallback agent url is not a valid `http://host[:port]` url, or the `endpoint_url` is not a valid `http://` url.
+    /// `https://` urls are only valid with the `tls` feature or with [`Client::with_connector`](struct.Client.html#method.with_connector).
+    InvalidAgentUrl(String),
+    /// The `socket_path` is set, but unix domain sockets are not supported on this platform.
+    UnixSocketUnsupported,
+    /// The `fallback_agent_urls` are set, but the client sends to a unix domain socket.
+    UnixSocketFallback,
+    /// A custom connector is given, but the client is configured to send to a unix domain socket.
+    UnixSocketConnector,
+    /// The `sample_rate` is not between `0.0` and `1.0`.
+    InvalidSampleRate(f64),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidAgentUrl(url) => write!(f, "invalid datadog agent url: {}", url),
+            ConfigError::UnixSocketUnsupported => {
+                write!(f, "unix domain sockets are not supported on this platform")
+            }
+            ConfigError::UnixSocketFallback => write!(
+                f,
+                "fallback agent urls are not supported with a unix domain socket"
+            ),
+            ConfigError::UnixSocketConnector => write!(
+                f,
+                "custom connectors are not supported with a unix domain socket"
+            ),
+            ConfigError::InvalidSampleRate(rate) => {
+                write!(f, "sample rate {} is not between 0 and 1", rate)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Errors returned when a trace can not be enqueued to be sent, or when it is invalid.
+#[derive(Debug)]
+pub enum TraceError {
+    /// The buffer queue is full, the trace is handed back to the caller.
+    QueueFull(Trace),
+    /// The buffer consumer task is not running anymore.
+    Disconnected,
+    /// The `parent_id` of a span is not the id of another span in the trace.
+    UnknownParentId { span_id: u64, parent_id: u64 },
+    /// More than one span in the trace has this id.
+    DuplicateSpanId(u64),
+    /// The trace could not be encoded to msgpack.
+    Encode(String),
+}
+
+impl fmt::Display for TraceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceError::QueueFull(_) => write!(f, "buffer queue is full"),
+            TraceError::Disconnected => write!(f, "buffer consumer task is not running"),
+            TraceError::UnknownParentId { span_id, parent_id } => write!(
+                f,
+                "span {} has parent {} that is not in the trace",
+                span_id, parent_id
+            ),
+            TraceError::DuplicateSpanId(id) => write!(f, "more than one span has id {}", id),
+            TraceError::Encode(err) => write!(f, "could not encode trace: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for TraceError {}
@@ -0,0 +1,59 @@
+//! Random trace and span ids, like the ones of `Trace::new` and `Span::new`.
+//!
+//! ```
+//! use datadog_apm::{id, Priority, Span, Trace};
+//!
+//! let mut trace = Trace::new(Priority::AutoKeep);
+//! trace.id = u128::from(id::generate_trace_id());
+//! trace.spans.push(Span::builder("request", "GET /path").id(id::generate_span_id()).build());
+//! ```
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static COUNTER: AtomicU64 = AtomicU64::new(0);
+
+thread_local! {
+    static RANDOM_STATE: RandomState = RandomState::new();
+}
+
+/// Returns a random trace id between `1` and `2^63 - 1`, valid for the datadog agent.
+pub fn generate_trace_id() -> u64 {
+    random_id()
+}
+
+/// Returns a random span id between `1` and `2^63 - 1`, valid for the datadog agent.
+pub fn generate_span_id() -> u64 {
+    random_id()
+}
+
+/// Returns a random id between `1` and `2^63 - 1`.
+///
+/// The top bit is always unset, since some tools read the ids as signed 64-bit integers.
+pub(crate) fn random_id() -> u64 {
+    loop {
+        // hashing a counter with the randomly keyed std hasher is enough for unique ids,
+        // without depending on a random number generator crate
+        let mut hasher = RANDOM_STATE.with(|state| state.build_hasher());
+        hasher.write_u64(COUNTER.fetch_add(1, Ordering::Relaxed));
+        let id = hasher.finish() >> 1;
+        if id != 0 {
+            return id;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generated_ids() {
+        let ids = (0..1000)
+            .flat_map(|_| vec![generate_trace_id(), generate_span_id()])
+            .collect::<std::collections::HashSet<_>>();
+        assert_eq!(ids.len(), 2000);
+        assert!(ids.iter().all(|id| *id != 0 && *id <= i64::MAX as u64));
+    }
+}
@@ -19,7 +19,7 @@
 //!```
 //!
 //! - Create the client:
-//! (remember to reuse the same client instead of create a new one everytime, so the buffer can work)
+//!   (remember to reuse the same client instead of create a new one everytime, so the buffer can work)
 //! ```no_run
 //! use datadog_apm::{Client, Config};
 //!
@@ -31,27 +31,31 @@
 //! ```
 //!
 //! - create a trace with spans:
-//! (for this example there is a span for a http request and a child-span for the sql transaction)
+//!   (for this example there is a span for a http request and a child-span for the sql transaction)
 //! ```
-//! use datadog_apm::{Trace, Span, HttpInfo, ErrorInfo, SqlInfo};
+//! use datadog_apm::{Trace, Priority, Span, SpanType, HttpInfo, ErrorInfo, SqlInfo};
 //! use std::collections::HashMap;
 //! use std::time::{Duration, SystemTime};
 //!
 //! let trace = Trace {
 //!     id: 123,
-//!     priority: 1,
+//!     priority: Priority::AutoKeep,
+//!     origin: None,
 //!     spans: vec![Span {
 //!          id: 1,
 //!          parent_id: None,
 //!          name: "request".to_string(),
-//!          resource: "GET /path".to_string(),
-//!          r#type: "web".to_string(),
+//!          resource: "GET /path/:id".to_string(),
+//!          r#type: SpanType::Web,
 //!          start: SystemTime::now(),
 //!          duration: Duration::from_millis(50),
 //!          http: Some(HttpInfo {
 //!              url: String::from("/path/2?param=true"),
 //!              method: String::from("GET"),
 //!              status_code: String::from("500"),
+//!              request_size: None,
+//!              response_size: Some(21),
+//!              route: Some(String::from("/path/:id")),
 //!          }),
 //!          error: Some(ErrorInfo {
 //!             r#type: "unknown".to_string(),
@@ -59,13 +63,20 @@
 //!             stack: "stack here".to_string(),
 //!          }),
 //!          sql: None,
+//!          messaging: None,
+//!          cache: None,
 //!          tags: HashMap::new(),
+//!          metrics: HashMap::new(),
+//!          service: None,
+//!          measured: false,
+//!          analytics_rate: None,
+//!          links: Vec::new(),
 //!     }, Span {
 //!          id: 2,
 //!          parent_id: Some(1),
 //!          name: "database".to_string(),
 //!          resource: "select".to_string(),
-//!          r#type: "db".to_string(),
+//!          r#type: SpanType::Db,
 //!          start: SystemTime::now(),
 //!          duration: Duration::from_millis(20),
 //!          http: None,
@@ -75,28 +86,54 @@
 //!             rows: "1".to_string(),
 //!             db: "test".to_string(),
 //!          }),
+//!          messaging: None,
+//!          cache: None,
 //!          tags: HashMap::new(),
+//!          metrics: HashMap::new(),
+//!          service: None,
+//!          measured: false,
+//!          analytics_rate: None,
+//!          links: Vec::new(),
 //!     }]
 //! };
 //! ```
 //!
+//! Spans can also be created with [`Span::builder`](struct.Span.html#method.builder), that defaults the optional fields.
+//!
 //! - send the trace:
 //! ```not_run
-//! client.send_trace(trace);
+//! client.send_trace(trace).unwrap();
 //! ```
 //!
 //! And that's it! The trace will be buffered and sent without block the current caller.
 //!
+//! - before the process exits, shutdown the client so the buffered traces are sent:
+//! ```not_run
+//! client.shutdown().await;
+//! ```
+//!
+//!
+//! # Distributed tracing
+//!
+//! The [`propagation`](propagation/index.html) module reads and writes the trace context in the datadog http headers,
+//! to continue the traces of the callers of a service.
+//!
 //!
 //! # Config
 //!
 //! Check [`Config`](struct.Config.html) for all available configurations.
+//! [`Config::from_env`](struct.Config.html#method.from_env) reads the standard `DD_*` environment variables.
 //!
 //!
 //! # Features that are not included yet: (Contributions welcome!)
 //!
 //! - [ ] [async-std](https://github.com/async-rs/async-std) support.
-//! - [ ] [tracing](https://github.com/tokio-rs/tracing) integration.
+//! - [x] [tracing](https://github.com/tokio-rs/tracing) integration, with the `tracing` feature.
+//! - [x] `https://` agent urls, with the `tls` feature.
+//! - [x] synchronous code support, with the `blocking` feature.
+//! - [x] actix-web request spans, with the `actix` feature.
+//! - [x] OpenTelemetry span exporter, with the `opentelemetry` feature.
+//! - [x] error logs of the `log` crate recorded in the spans, see the [`logger`](logger/index.html) module.
 //!
 #[macro_use]
 extern crate log;
@@ -104,6 +141,29 @@ extern crate rmp;
 extern crate rmp_serde as rmps;
 extern crate serde;
 
+#[cfg(feature = "actix")]
+pub mod actix;
+#[cfg(feature = "blocking")]
+pub mod blocking;
+mod builder;
 mod client;
+mod clock;
+mod connector;
+mod container;
+mod error;
+pub mod id;
+pub mod logger;
+#[cfg(feature = "opentelemetry")]
+pub mod opentelemetry;
+pub mod propagation;
+#[cfg(test)]
+mod testing;
+#[cfg(feature = "tracing")]
+pub mod tracing;
 
-pub use crate::client::{Client, Config, ErrorInfo, HttpInfo, Span, SqlInfo, Trace};
+pub use crate::builder::SpanBuilder;
+pub use crate::client::{
+    ApiVersion, CacheInfo, Client, ClientStats, Compression, Config, DropCallback, ErrorInfo,
+    HttpInfo, MessagingInfo, Priority, Span, SpanLink, SpanType, SqlInfo, Trace,
+};
+pub use crate::error::{ConfigError, TraceError};
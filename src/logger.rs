@@ -0,0 +1,233 @@
+//! [log](https://docs.rs/log) integration, recording the error logs in the span of the code logging them.
+//!
+//! Wrap the logger of the application in an [`ErrorLogger`](struct.ErrorLogger.html), and run the code of a span
+//! in [`capture`](fn.capture.html) or [`capture_async`](fn.capture_async.html): the first `error!` logged inside it
+//! is returned as an [`ErrorInfo`](../struct.ErrorInfo.html) to set on the span.
+//! ```
+//! use datadog_apm::logger::{self, ErrorLogger};
+//! use datadog_apm::Span;
+//! use log::{error, Log, Metadata, Record};
+//!
+//! struct StdoutLogger;
+//!
+//! impl Log for StdoutLogger {
+//!     fn enabled(&self, _: &Metadata) -> bool {
+//!         true
+//!     }
+//!
+//!     fn log(&self, record: &Record) {
+//!         println!("{}", record.args());
+//!     }
+//!
+//!     fn flush(&self) {}
+//! }
+//!
+//! static LOGGER: ErrorLogger<StdoutLogger> = ErrorLogger::new(StdoutLogger);
+//! log::set_logger(&LOGGER).unwrap();
+//! log::set_max_level(log::LevelFilter::Info);
+//!
+//! let mut span = Span::new("job", "send emails");
+//! let (_, error) = logger::capture(|| {
+//!     error!("smtp server unreachable");
+//! });
+//! span.error = error;
+//! span.finish();
+//! assert_eq!(span.error.unwrap().msg, "smtp server unreachable");
+//! ```
+//!
+//! The errors are captured per thread: in async code use `capture_async`, that captures the errors logged while
+//! the future is polled, wherever the runtime polls it.
+
+use crate::client::ErrorInfo;
+
+use log::{Level, Log, Metadata, Record};
+
+use std::cell::RefCell;
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+thread_local! {
+    /// The errors of the running captures, the innermost last.
+    static CAPTURES: RefCell<Vec<Option<ErrorInfo>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// A `log::Log` recording the `Error` level logs in the running capture, and forwarding every log to the inner logger.
+#[derive(Debug)]
+pub struct ErrorLogger<L> {
+    inner: L,
+}
+
+impl<L: Log> ErrorLogger<L> {
+    pub const fn new(inner: L) -> ErrorLogger<L> {
+        ErrorLogger { inner }
+    }
+}
+
+impl<L: Log> Log for ErrorLogger<L> {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() == Level::Error || self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if record.level() == Level::Error {
+            CAPTURES.with(|captures| {
+                if let Some(error @ None) = captures.borrow_mut().last_mut() {
+                    *error = Some(ErrorInfo {
+                        r#type: record.target().to_string(),
+                        msg: record.args().to_string(),
+                        stack: String::new(),
+                    });
+                }
+            });
+        }
+        if self.inner.enabled(record.metadata()) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Runs `f`, returning its result and the first error it logged, with the logger target as error type.
+///
+/// The errors are only recorded when the logger is an [`ErrorLogger`](struct.ErrorLogger.html).
+/// A nested capture keeps the errors logged inside it.
+pub fn capture<R>(f: impl FnOnce() -> R) -> (R, Option<ErrorInfo>) {
+    CAPTURES.with(|captures| captures.borrow_mut().push(None));
+    // pops the capture even when `f` panics
+    struct Pop;
+    impl Drop for Pop {
+        fn drop(&mut self) {
+            CAPTURES.with(|captures| captures.borrow_mut().pop());
+        }
+    }
+    let pop = Pop;
+    let result = f();
+    let error = CAPTURES.with(|captures| captures.borrow_mut().last_mut().and_then(Option::take));
+    drop(pop);
+    (result, error)
+}
+
+/// Runs the future, resolving to its output and the first error logged while it was polled.
+///
+/// See [`capture`](fn.capture.html).
+pub fn capture_async<F: Future>(future: F) -> CaptureErrors<F> {
+    CaptureErrors {
+        future: Box::pin(future),
+        error: None,
+    }
+}
+
+/// The future returned by [`capture_async`](fn.capture_async.html).
+#[derive(Debug)]
+pub struct CaptureErrors<F> {
+    future: Pin<Box<F>>,
+    error: Option<ErrorInfo>,
+}
+
+impl<F: Future> Future for CaptureErrors<F> {
+    type Output = (F::Output, Option<ErrorInfo>);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let (poll, error) = capture(|| self.future.as_mut().poll(cx));
+        if self.error.is_none() {
+            self.error = error;
+        }
+        match poll {
+            Poll::Ready(output) => Poll::Ready((output, self.error.take())),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use log::LevelFilter;
+
+    use std::sync::{Mutex, Once};
+
+    static INIT: Once = Once::new();
+    static LOGGER: ErrorLogger<MemoryLogger> = ErrorLogger::new(MemoryLogger);
+    static LOGGED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+    /// Inner logger keeping the messages of the `Info` and higher levels.
+    struct MemoryLogger;
+
+    impl Log for MemoryLogger {
+        fn enabled(&self, metadata: &Metadata) -> bool {
+            metadata.level() <= Level::Info
+        }
+
+        fn log(&self, record: &Record) {
+            LOGGED.lock().unwrap().push(record.args().to_string());
+        }
+
+        fn flush(&self) {}
+    }
+
+    fn init() {
+        INIT.call_once(|| {
+            log::set_logger(&LOGGER).unwrap();
+            log::set_max_level(LevelFilter::Trace);
+        });
+    }
+
+    #[test]
+    fn test_capture() {
+        init();
+        let (result, error) = capture(|| {
+            log::warn!(target: "test_capture", "not an error");
+            log::error!(target: "test_capture", "first {}", 1);
+            log::error!(target: "test_capture", "second");
+            "done"
+        });
+        assert_eq!(result, "done");
+        let error = error.unwrap();
+        assert_eq!(error.r#type, "test_capture");
+        assert_eq!(error.msg, "first 1");
+
+        let (_, error) = capture(|| log::debug!("nothing wrong"));
+        assert!(error.is_none());
+
+        let logged = LOGGED.lock().unwrap();
+        assert!(logged.iter().any(|msg| msg == "not an error"));
+        assert!(logged.iter().any(|msg| msg == "first 1"));
+        assert!(!logged.iter().any(|msg| msg == "nothing wrong"));
+    }
+
+    #[test]
+    fn test_nested_capture() {
+        init();
+        let (inner_error, outer_error) = capture(|| {
+            let (_, inner_error) = capture(|| log::error!("inner"));
+            log::error!("outer");
+            inner_error
+        });
+        assert_eq!(inner_error.unwrap().msg, "inner");
+        assert_eq!(outer_error.unwrap().msg, "outer");
+
+        // outside a capture the errors are only forwarded
+        log::error!("uncaptured");
+        assert!(CAPTURES.with(|captures| captures.borrow().is_empty()));
+    }
+
+    #[tokio::test]
+    async fn test_capture_async() {
+        init();
+        let (result, error) = capture_async(async {
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            log::error!("after a delay");
+            tokio::time::delay_for(std::time::Duration::from_millis(1)).await;
+            log::error!("later");
+            1
+        })
+        .await;
+        assert_eq!(result, 1);
+        assert_eq!(error.unwrap().msg, "after a delay");
+    }
+}
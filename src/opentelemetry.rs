@@ -0,0 +1,241 @@
+//! [OpenTelemetry](https://opentelemetry.io) integration, enabled with the `opentelemetry` feature.
+//!
+//! Register a [`DatadogExporter`](struct.DatadogExporter.html) on an `opentelemetry_sdk` tracer provider
+//! and the finished spans are sent to datadog through a [`Client`](../struct.Client.html):
+//! ```no_run
+//! use datadog_apm::opentelemetry::DatadogExporter;
+//! use datadog_apm::{Client, Config};
+//! use opentelemetry_sdk::trace::TracerProvider;
+//!
+//! let client = Client::new(Config {
+//!     service: "my-crate".to_string(),
+//!     ..Default::default()
+//! });
+//! let provider = TracerProvider::builder()
+//!     .with_simple_exporter(DatadogExporter::new(client))
+//!     .build();
+//! ```
+//!
+//! The spans of a batch are grouped in a trace by trace id, the datadog agent joins the traces sent in several parts.
+//! A span is mapped to a datadog span with:
+//! - the OpenTelemetry name as resource, and the instrumentation library and the kind as name, like `my-crate.server`;
+//! - a type from the kind: `web` for a server span, `http` for a client span and `queue` for a producer or consumer span;
+//! - the numeric attributes as metrics, and the other attributes as tags, plus a `span.kind` tag;
+//! - an [`ErrorInfo`](../struct.ErrorInfo.html) with the description of an `Error` status.
+
+use crate::client::{Client, ErrorInfo, Priority, Span, SpanType, Trace};
+
+use opentelemetry::trace::{SpanId, SpanKind, Status, TraceError};
+use opentelemetry::Value;
+use opentelemetry_sdk::export::trace::{ExportResult, SpanData, SpanExporter};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+
+/// An OpenTelemetry `SpanExporter` sending the spans to datadog through a [`Client`](../struct.Client.html).
+///
+/// Shutting the exporter down does not send the buffered traces, call [`Client::shutdown`](../struct.Client.html#method.shutdown)
+/// before exiting.
+#[derive(Debug, Clone)]
+pub struct DatadogExporter {
+    client: Client,
+}
+
+impl DatadogExporter {
+    pub fn new(client: Client) -> DatadogExporter {
+        DatadogExporter { client }
+    }
+}
+
+impl SpanExporter for DatadogExporter {
+    fn export(
+        &mut self,
+        batch: Vec<SpanData>,
+    ) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let mut result = Ok(());
+        for trace in map_to_traces(batch) {
+            if let Err(err) = self.client.send_trace(trace) {
+                result = Err(TraceError::from(err.to_string()));
+            }
+        }
+        Box::pin(std::future::ready(result))
+    }
+
+    fn force_flush(&mut self) -> Pin<Box<dyn Future<Output = ExportResult> + Send + 'static>> {
+        let client = self.client.clone();
+        Box::pin(async move {
+            client.flush().await;
+            Ok(())
+        })
+    }
+}
+
+/// Groups the spans in traces by trace id, keeping the order of the batch.
+fn map_to_traces(batch: Vec<SpanData>) -> Vec<Trace> {
+    let mut traces: Vec<Trace> = Vec::new();
+    let mut indexes = HashMap::new();
+    for span_data in batch {
+        let trace_id = u128::from_be_bytes(span_data.span_context.trace_id().to_bytes());
+        let index = *indexes.entry(trace_id).or_insert_with(|| {
+            let mut trace = Trace::new(Priority::AutoKeep);
+            trace.id = trace_id;
+            traces.push(trace);
+            traces.len() - 1
+        });
+        traces[index].spans.push(map_to_span(span_data));
+    }
+    traces
+}
+
+fn map_to_span(span_data: SpanData) -> Span {
+    let kind = match span_data.span_kind {
+        SpanKind::Client => "client",
+        SpanKind::Server => "server",
+        SpanKind::Producer => "producer",
+        SpanKind::Consumer => "consumer",
+        SpanKind::Internal => "internal",
+    };
+    let r#type = match span_data.span_kind {
+        SpanKind::Server => SpanType::Web,
+        SpanKind::Client => SpanType::Http,
+        SpanKind::Producer | SpanKind::Consumer => SpanType::Queue,
+        SpanKind::Internal => SpanType::from("custom"),
+    };
+
+    let mut span = Span::builder(
+        format!("{}.{}", span_data.instrumentation_lib.name, kind),
+        span_data.name.into_owned(),
+    )
+    .id(u64::from_be_bytes(
+        span_data.span_context.span_id().to_bytes(),
+    ))
+    .r#type(r#type)
+    .start(span_data.start_time)
+    .end(span_data.end_time)
+    .tag("span.kind", kind);
+    if span_data.parent_span_id != SpanId::INVALID {
+        span = span.parent_id(u64::from_be_bytes(span_data.parent_span_id.to_bytes()));
+    }
+    for attribute in span_data.attributes {
+        let key = attribute.key.as_str().to_string();
+        span = match attribute.value {
+            Value::I64(value) => span.metric(key, value as f64),
+            Value::F64(value) => span.metric(key, value),
+            value => span.tag(key, value.to_string()),
+        };
+    }
+    if let Status::Error { description } = span_data.status {
+        span = span.error(ErrorInfo {
+            r#type: "error".to_string(),
+            msg: description.into_owned(),
+            stack: String::new(),
+        });
+    }
+    span.build()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Config;
+    use crate::testing::recording_client;
+
+    use opentelemetry::trace::{SpanContext, TraceFlags, TraceId, TraceState};
+    use opentelemetry::KeyValue;
+    use opentelemetry_sdk::trace::EvictedQueue;
+    use opentelemetry_sdk::{InstrumentationLibrary, Resource};
+
+    use std::borrow::Cow;
+    use std::time::{Duration, SystemTime};
+
+    fn span_data(trace_id: u128, span_id: u64, parent_span_id: u64) -> SpanData {
+        let start_time = SystemTime::now();
+        SpanData {
+            span_context: SpanContext::new(
+                TraceId::from(trace_id),
+                SpanId::from(span_id),
+                TraceFlags::SAMPLED,
+                false,
+                TraceState::default(),
+            ),
+            parent_span_id: SpanId::from(parent_span_id),
+            span_kind: SpanKind::Server,
+            name: Cow::Borrowed("GET /users/:id"),
+            start_time,
+            end_time: start_time + Duration::from_millis(20),
+            attributes: Vec::new(),
+            dropped_attributes_count: 0,
+            events: EvictedQueue::new(0),
+            links: EvictedQueue::new(0),
+            status: Status::Unset,
+            resource: Cow::Owned(Resource::empty()),
+            instrumentation_lib: InstrumentationLibrary::new(
+                "my-crate",
+                None::<&str>,
+                None::<&str>,
+                None,
+            ),
+        }
+    }
+
+    #[test]
+    fn test_map_to_span() {
+        let mut data = span_data(1, 2, 0);
+        data.attributes = vec![
+            KeyValue::new("http.method", "GET"),
+            KeyValue::new("retries", 2),
+            KeyValue::new("cached", false),
+        ];
+        let span = map_to_span(data.clone());
+        assert_eq!(span.id, 2);
+        assert_eq!(span.parent_id, None);
+        assert_eq!(span.name, "my-crate.server");
+        assert_eq!(span.resource, "GET /users/:id");
+        assert_eq!(span.r#type, SpanType::Web);
+        assert_eq!(span.start, data.start_time);
+        assert_eq!(span.duration, Duration::from_millis(20));
+        assert_eq!(span.tags["span.kind"], "server");
+        assert_eq!(span.tags["http.method"], "GET");
+        assert_eq!(span.tags["cached"], "false");
+        assert_eq!(span.metrics["retries"], 2.0);
+        assert!(span.error.is_none());
+
+        let mut data = span_data(1, 3, 2);
+        data.span_kind = SpanKind::Client;
+        data.status = Status::error("connection refused");
+        let span = map_to_span(data);
+        assert_eq!(span.parent_id, Some(2));
+        assert_eq!(span.r#type, SpanType::Http);
+        assert_eq!(span.error.unwrap().msg, "connection refused");
+    }
+
+    #[tokio::test]
+    async fn test_export_groups_spans_by_trace() {
+        let (client, connector) = recording_client(Config::default());
+
+        let trace_id = u128::MAX - 1;
+        let batch = vec![
+            span_data(trace_id, 2, 1),
+            span_data(7, 5, 0),
+            span_data(trace_id, 1, 0),
+        ];
+        let mut exporter = DatadogExporter::new(client);
+        assert!(exporter.export(batch).await.is_ok());
+        assert!(exporter.force_flush().await.is_ok());
+
+        let traces = connector.traces();
+        assert_eq!(traces.len(), 2);
+        let spans = traces[0].as_array().unwrap();
+        let span_ids = spans
+            .iter()
+            .map(|span| span["span_id"].as_u64().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(span_ids, vec![2, 1]);
+        assert_eq!(spans[0]["trace_id"], u64::MAX - 1);
+        assert_eq!(spans[0]["meta"]["_dd.p.tid"], "ffffffffffffffff");
+        assert_eq!(spans[0]["parent_id"], 1);
+        assert_eq!(traces[1][0]["trace_id"], 7);
+        assert_eq!(traces[1][0]["span_id"], 5);
+    }
+}
@@ -0,0 +1,281 @@
+//! Distributed tracing, reading and writing the trace context in the datadog http headers.
+//!
+//! A service receiving a request continues the trace of the caller by extracting its context,
+//! and passes its own context to the services it calls by injecting it in the request headers:
+//! ```
+//! use datadog_apm::propagation::{self, TraceContext};
+//! use datadog_apm::{Span, Trace};
+//! use hyper::HeaderMap;
+//!
+//! # let mut incoming = HeaderMap::new();
+//! # incoming.insert("x-datadog-trace-id", "123".parse().unwrap());
+//! # incoming.insert("x-datadog-parent-id", "456".parse().unwrap());
+//! let context = TraceContext::from_headers(&incoming).unwrap();
+//! let request = Span::builder("request", "GET /path")
+//!     .parent_id(context.parent_id)
+//!     .build();
+//!
+//! let mut outgoing = HeaderMap::new();
+//! propagation::inject(
+//!     &TraceContext {
+//!         trace_id: context.trace_id,
+//!         parent_id: request.id,
+//!         priority: context.priority,
+//!     },
+//!     &mut outgoing,
+//! );
+//!
+//! let mut trace = Trace::new(context.priority.unwrap_or_default());
+//! trace.id = context.trace_id;
+//! trace.spans.push(request);
+//! ```
+//!
+//! The 128-bit trace ids, like the OpenTelemetry ones, are propagated with their high 64 bits in the `_dd.p.tid`
+//! tag of the `x-datadog-tags` header, as the official datadog libraries do.
+//!
+//! The local root span of a continued trace has a parent in another service, so the trace does not
+//! pass [`Trace::validate`](../struct.Trace.html#method.validate).
+
+use crate::client::Priority;
+
+use hyper::header::{HeaderMap, HeaderValue};
+
+pub const TRACE_ID_HEADER: &str = "x-datadog-trace-id";
+pub const PARENT_ID_HEADER: &str = "x-datadog-parent-id";
+pub const SAMPLING_PRIORITY_HEADER: &str = "x-datadog-sampling-priority";
+pub const TAGS_HEADER: &str = "x-datadog-tags";
+
+/// Tag of the `x-datadog-tags` header with the high 64 bits of the trace id, as 16 hexadecimal digits.
+const TRACE_ID_HIGH_TAG: &str = "_dd.p.tid";
+
+/// Trace and span of the caller of a service.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TraceContext {
+    /// Trace id, up to 128 bits like [`Trace::id`](../struct.Trace.html#structfield.id).
+    pub trace_id: u128,
+    /// Id of the caller span, the parent of the spans of the service.
+    pub parent_id: u64,
+    /// Sampling priority decided by the caller, if any.
+    pub priority: Option<Priority>,
+}
+
+impl TraceContext {
+    /// Reads the trace context from the datadog headers, see [`extract`](fn.extract.html).
+    pub fn from_headers(headers: &HeaderMap) -> Option<TraceContext> {
+        extract(headers)
+    }
+
+    /// Returns the datadog headers to pass the trace context to a downstream service, see [`inject`](fn.inject.html).
+    pub fn to_headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        inject(self, &mut headers);
+        headers
+    }
+}
+
+/// Reads the trace context from the datadog headers.
+///
+/// Returns `None` when the trace id or the parent id is missing or invalid. An invalid sampling priority is ignored,
+/// and so is an invalid `_dd.p.tid` tag, keeping only the low 64 bits of the trace id.
+pub fn extract(headers: &HeaderMap) -> Option<TraceContext> {
+    let trace_id = header_value::<u64>(headers, TRACE_ID_HEADER).filter(|id| *id != 0)?;
+    let parent_id = header_value::<u64>(headers, PARENT_ID_HEADER).filter(|id| *id != 0)?;
+    let priority = header_value::<i32>(headers, SAMPLING_PRIORITY_HEADER).and_then(priority_from);
+    let trace_id_high = header_value::<String>(headers, TAGS_HEADER)
+        .and_then(|tags| trace_id_high_from(&tags))
+        .unwrap_or(0);
+    Some(TraceContext {
+        trace_id: u128::from(trace_id_high) << 64 | u128::from(trace_id),
+        parent_id,
+        priority,
+    })
+}
+
+/// Writes the trace context to the datadog headers, replacing any previous values.
+///
+/// The `x-datadog-tags` header is only written for a trace id above 64 bits, and removed otherwise.
+pub fn inject(context: &TraceContext, headers: &mut HeaderMap) {
+    let trace_id_high = (context.trace_id >> 64) as u64;
+    headers.insert(TRACE_ID_HEADER, HeaderValue::from(context.trace_id as u64));
+    headers.insert(PARENT_ID_HEADER, HeaderValue::from(context.parent_id));
+    if trace_id_high != 0 {
+        let tags = format!("{}={:016x}", TRACE_ID_HIGH_TAG, trace_id_high);
+        headers.insert(TAGS_HEADER, HeaderValue::from_str(&tags).unwrap());
+    } else {
+        headers.remove(TAGS_HEADER);
+    }
+    match context.priority {
+        Some(priority) => {
+            headers.insert(
+                SAMPLING_PRIORITY_HEADER,
+                HeaderValue::from(priority.value()),
+            );
+        }
+        None => {
+            headers.remove(SAMPLING_PRIORITY_HEADER);
+        }
+    }
+}
+
+fn header_value<T: std::str::FromStr>(headers: &HeaderMap, name: &str) -> Option<T> {
+    headers.get(name)?.to_str().ok()?.trim().parse().ok()
+}
+
+/// Reads the `_dd.p.tid` tag from the comma separated `key=value` tags of the `x-datadog-tags` header.
+fn trace_id_high_from(tags: &str) -> Option<u64> {
+    let value = tags.split(',').find_map(|tag| {
+        let mut parts = tag.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(key), Some(value)) if key.trim() == TRACE_ID_HIGH_TAG => Some(value.trim()),
+            _ => None,
+        }
+    })?;
+    if value.len() != 16 || !value.bytes().all(|byte| byte.is_ascii_hexdigit()) {
+        return None;
+    }
+    u64::from_str_radix(value, 16).ok()
+}
+
+fn priority_from(value: i32) -> Option<Priority> {
+    match value {
+        -1 => Some(Priority::UserReject),
+        0 => Some(Priority::AutoReject),
+        1 => Some(Priority::AutoKeep),
+        2 => Some(Priority::UserKeep),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_inject_and_extract() {
+        let context = TraceContext {
+            trace_id: 123,
+            parent_id: u64::MAX,
+            priority: Some(Priority::UserReject),
+        };
+        let mut headers = HeaderMap::new();
+        inject(&context, &mut headers);
+
+        assert_eq!(headers[TRACE_ID_HEADER], "123");
+        assert_eq!(headers[PARENT_ID_HEADER], "18446744073709551615");
+        assert_eq!(headers[SAMPLING_PRIORITY_HEADER], "-1");
+        assert_eq!(extract(&headers), Some(context));
+
+        let context = TraceContext {
+            priority: None,
+            ..context
+        };
+        inject(&context, &mut headers);
+        assert!(!headers.contains_key(SAMPLING_PRIORITY_HEADER));
+        assert_eq!(extract(&headers), Some(context));
+    }
+
+    #[test]
+    fn test_inject_and_extract_128_bit_trace_id() {
+        let context = TraceContext {
+            trace_id: u128::from(u64::MAX) + 0x2a,
+            parent_id: 2,
+            priority: None,
+        };
+        let mut headers = HeaderMap::new();
+        inject(&context, &mut headers);
+
+        assert_eq!(headers[TRACE_ID_HEADER], "41");
+        assert_eq!(headers[TAGS_HEADER], "_dd.p.tid=0000000000000001");
+        assert_eq!(extract(&headers), Some(context));
+
+        let context = TraceContext {
+            trace_id: u128::MAX,
+            ..context
+        };
+        assert_eq!(
+            TraceContext::from_headers(&context.to_headers()),
+            Some(context)
+        );
+
+        // a 64-bit trace id does not keep the tags of a previous one
+        let context = TraceContext {
+            trace_id: 3,
+            ..context
+        };
+        inject(&context, &mut headers);
+        assert!(!headers.contains_key(TAGS_HEADER));
+        assert_eq!(extract(&headers), Some(context));
+    }
+
+    #[test]
+    fn test_extract_trace_id_high_tag() {
+        let trace_id = |tags: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(TRACE_ID_HEADER, "1".parse().unwrap());
+            headers.insert(PARENT_ID_HEADER, "2".parse().unwrap());
+            headers.insert(TAGS_HEADER, tags.parse().unwrap());
+            extract(&headers).unwrap().trace_id
+        };
+
+        assert_eq!(
+            trace_id("_dd.p.dm=-0,_dd.p.tid=640cfd8d00000000"),
+            0x640cfd8d00000000_0000000000000001
+        );
+        assert_eq!(trace_id("_dd.p.dm=-0"), 1);
+        assert_eq!(trace_id("_dd.p.tid=640cfd8d"), 1);
+        assert_eq!(trace_id("_dd.p.tid=+640cfd8d0000000"), 1);
+        assert_eq!(trace_id("_dd.p.tid=zzzzzzzzzzzzzzzz"), 1);
+    }
+
+    #[test]
+    fn test_from_headers_and_to_headers() {
+        let context = TraceContext {
+            trace_id: 1,
+            parent_id: 2,
+            priority: Some(Priority::UserKeep),
+        };
+        let headers = context.to_headers();
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers[SAMPLING_PRIORITY_HEADER], "2");
+        assert_eq!(TraceContext::from_headers(&headers), Some(context));
+
+        let mut malformed = headers.clone();
+        malformed.insert(TRACE_ID_HEADER, "-1".parse().unwrap());
+        assert_eq!(TraceContext::from_headers(&malformed), None);
+        assert_eq!(TraceContext::from_headers(&HeaderMap::new()), None);
+    }
+
+    #[test]
+    fn test_extract_invalid_headers() {
+        let headers = |values: &[(&'static str, &str)]| {
+            let mut headers = HeaderMap::new();
+            for (name, value) in values {
+                headers.insert(*name, value.parse().unwrap());
+            }
+            headers
+        };
+
+        assert_eq!(extract(&HeaderMap::new()), None);
+        assert_eq!(extract(&headers(&[(TRACE_ID_HEADER, "1")])), None);
+        assert_eq!(
+            extract(&headers(&[
+                (TRACE_ID_HEADER, "1"),
+                (PARENT_ID_HEADER, "abc")
+            ])),
+            None
+        );
+        assert_eq!(
+            extract(&headers(&[(TRACE_ID_HEADER, "0"), (PARENT_ID_HEADER, "2")])),
+            None
+        );
+
+        let context = extract(&headers(&[
+            (TRACE_ID_HEADER, "1"),
+            (PARENT_ID_HEADER, "2"),
+            (SAMPLING_PRIORITY_HEADER, "5"),
+        ]))
+        .unwrap();
+        assert_eq!((context.trace_id, context.parent_id), (1, 2));
+        assert_eq!(context.priority, None);
+    }
+}
@@ -0,0 +1,199 @@
+//! Test helpers shared by the client and the integration modules.
+
+use crate::client::{ApiVersion, Client, Config};
+use crate::clock::{Clock, SystemClock};
+
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper::Uri;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use std::future::{ready, Ready};
+use std::io;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// Returns a client sending the traces to a [`RecordingConnector`](struct.RecordingConnector.html),
+/// with the `v0.4` api so the recorded traces can be decoded with `RecordingConnector::traces`.
+pub(crate) fn recording_client(config: Config) -> (Client, RecordingConnector) {
+    recording_client_with_clock(config, Arc::new(SystemClock))
+}
+
+/// Like [`recording_client`](fn.recording_client.html), with the flush interval timed by `clock`.
+pub(crate) fn recording_client_with_clock(
+    config: Config,
+    clock: Arc<dyn Clock>,
+) -> (Client, RecordingConnector) {
+    let connector = RecordingConnector::default();
+    let client = Client::with_connector_and_clock(
+        Config {
+            api_version: ApiVersion::V04,
+            ..config
+        },
+        connector.clone(),
+        clock,
+    )
+    .unwrap();
+    (client, connector)
+}
+
+/// A request received by a [`RecordingConnector`](struct.RecordingConnector.html).
+#[derive(Debug, Clone)]
+pub(crate) struct RecordedRequest {
+    /// The request line and the headers, each ending with `\r\n`.
+    pub(crate) head: String,
+    pub(crate) body: Vec<u8>,
+}
+
+/// Returns the raw http response to the last of the requests received so far, `None` to never respond.
+pub(crate) type Respond = fn(&[RecordedRequest]) -> Option<&'static str>;
+
+pub(crate) const OK_RESPONSE: &str = "HTTP/1.1 200 OK\r\ncontent-length: 2\r\n\r\nOK";
+
+/// Connector to an in memory datadog agent, recording the requests and answering `200 OK` by default.
+#[derive(Debug, Clone)]
+pub(crate) struct RecordingConnector {
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    respond: Respond,
+}
+
+impl Default for RecordingConnector {
+    fn default() -> Self {
+        RecordingConnector::responding(|_| Some(OK_RESPONSE))
+    }
+}
+
+impl RecordingConnector {
+    /// Creates a connector answering with the response returned by `respond`.
+    pub(crate) fn responding(respond: Respond) -> RecordingConnector {
+        RecordingConnector {
+            requests: Arc::default(),
+            respond,
+        }
+    }
+
+    /// Returns the requests received so far.
+    pub(crate) fn requests(&self) -> Vec<RecordedRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// Decodes the traces of the `v0.4` payloads received so far, as arrays of spans.
+    pub(crate) fn traces(&self) -> Vec<serde_json::Value> {
+        self.requests()
+            .iter()
+            .flat_map(|request| {
+                let traces: Vec<serde_json::Value> =
+                    rmp_serde::from_read_ref(&request.body).unwrap();
+                traces
+            })
+            .collect()
+    }
+}
+
+impl Service<Uri> for RecordingConnector {
+    type Response = RecordingStream;
+    type Error = io::Error;
+    type Future = Ready<Result<RecordingStream, io::Error>>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _: Uri) -> Self::Future {
+        ready(Ok(RecordingStream {
+            requests: self.requests.clone(),
+            respond: self.respond,
+            request: Vec::new(),
+            reader: None,
+        }))
+    }
+}
+
+/// Connection answering every request once it is completely written.
+pub(crate) struct RecordingStream {
+    requests: Arc<Mutex<Vec<RecordedRequest>>>,
+    respond: Respond,
+    /// The bytes written since the last response.
+    request: Vec<u8>,
+    reader: Option<Waker>,
+}
+
+impl RecordingStream {
+    /// Takes the request in its head and its body, once the body has the length of its `content-length` header.
+    fn take_request(&mut self) -> Option<RecordedRequest> {
+        let end = self
+            .request
+            .windows(4)
+            .position(|window| window == b"\r\n\r\n")?;
+        let head = String::from_utf8_lossy(&self.request[..end + 2]).into_owned();
+        let body = &self.request[end + 4..];
+        let content_length = head.lines().find_map(|line| {
+            line.to_lowercase()
+                .strip_prefix("content-length:")
+                .and_then(|len| len.trim().parse::<usize>().ok())
+        })?;
+        if body.len() < content_length {
+            return None;
+        }
+        let body = body[..content_length].to_vec();
+        self.request.drain(..end + 4 + content_length);
+        Some(RecordedRequest { head, body })
+    }
+}
+
+impl Connection for RecordingStream {
+    fn connected(&self) -> Connected {
+        Connected::new()
+    }
+}
+
+impl AsyncRead for RecordingStream {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let request = match self.take_request() {
+            Some(request) => request,
+            None => {
+                self.reader = Some(cx.waker().clone());
+                return Poll::Pending;
+            }
+        };
+        let response = {
+            let mut requests = self.requests.lock().unwrap();
+            requests.push(request);
+            (self.respond)(&requests)
+        };
+        match response {
+            Some(response) => {
+                buf[..response.len()].copy_from_slice(response.as_bytes());
+                Poll::Ready(Ok(response.len()))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl AsyncWrite for RecordingStream {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.request.extend_from_slice(buf);
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
@@ -0,0 +1,320 @@
+//! [tracing](https://github.com/tokio-rs/tracing) integration, enabled with the `tracing` feature.
+//!
+//! Register a [`DatadogLayer`](struct.DatadogLayer.html) on a `tracing_subscriber::Registry`
+//! and every root span is sent as a trace when it closes, together with the spans opened inside it:
+//! ```no_run
+//! use datadog_apm::tracing::DatadogLayer;
+//! use datadog_apm::{Client, Config};
+//! use tracing_subscriber::layer::SubscriberExt;
+//!
+//! let client = Client::new(Config {
+//!     service: "my-crate".to_string(),
+//!     ..Default::default()
+//! });
+//! let subscriber = tracing_subscriber::registry().with(DatadogLayer::new(client));
+//! ```
+//!
+//! Span fields are mapped to the datadog span:
+//! - `resource`, `span.type` and `service` replace the span resource, type and service;
+//! - `http.url`, `http.method`, `http.status_code`, `http.route`, `http.request.content_length`
+//!   and `http.response.content_length` fill the [`HttpInfo`](../struct.HttpInfo.html);
+//! - `sql.query`, `sql.rows` and `sql.db` fill the [`SqlInfo`](../struct.SqlInfo.html);
+//! - `messaging.system`, `messaging.destination` and `messaging.operation` fill the [`MessagingInfo`](../struct.MessagingInfo.html);
+//! - `cache.system`, `cache.command` and `cache.key` fill the [`CacheInfo`](../struct.CacheInfo.html);
+//! - any other field is added as a tag.
+//!
+//! An `ERROR` level event sets the [`ErrorInfo`](../struct.ErrorInfo.html) of the span it happens in,
+//! using its `message`, `error.type` and `error.stack` fields.
+
+use crate::client::{
+    CacheInfo, Client, ErrorInfo, HttpInfo, MessagingInfo, Priority, Span, SqlInfo, Trace,
+};
+use crate::id::random_id;
+
+use tracing_core::field::{Field, Visit};
+use tracing_core::span::{Attributes, Id, Record};
+use tracing_core::{Event, Level, Subscriber};
+use tracing_subscriber::layer::{Context, Layer};
+use tracing_subscriber::registry::LookupSpan;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+/// A `tracing_subscriber::Layer` that sends the closed tracing spans to datadog through a [`Client`](../struct.Client.html).
+///
+/// The trace id is generated when a root span is created and inherited by all of its children.
+/// Spans closing after their root span are not sent.
+#[derive(Debug, Clone)]
+pub struct DatadogLayer {
+    client: Client,
+}
+
+impl DatadogLayer {
+    pub fn new(client: Client) -> DatadogLayer {
+        DatadogLayer { client }
+    }
+}
+
+/// Datadog span under construction, stored in the tracing span extensions.
+struct SpanData {
+    trace_id: u64,
+    span: Span,
+    /// Closed spans of the trace, shared by the root span and all of its children.
+    finished: Arc<Mutex<Vec<Span>>>,
+}
+
+impl<S> Layer<S> for DatadogLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let tracing_span = match ctx.span(id) {
+            Some(span) => span,
+            None => return,
+        };
+
+        let parent = tracing_span.parent().and_then(|parent| {
+            parent
+                .extensions()
+                .get::<SpanData>()
+                .map(|data| (data.trace_id, data.span.id, data.finished.clone()))
+        });
+        let (trace_id, parent_id, finished) = match parent {
+            Some((trace_id, parent_id, finished)) => (trace_id, Some(parent_id), finished),
+            None => (random_id(), None, Arc::new(Mutex::new(Vec::new()))),
+        };
+
+        let name = attrs.metadata().name();
+        let mut span = Span::builder(name, name).build_with(self.client.clock());
+        span.parent_id = parent_id;
+        attrs.record(&mut SpanVisitor(&mut span));
+
+        tracing_span.extensions_mut().insert(SpanData {
+            trace_id,
+            span,
+            finished,
+        });
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        if let Some(tracing_span) = ctx.span(id) {
+            if let Some(data) = tracing_span.extensions_mut().get_mut::<SpanData>() {
+                values.record(&mut SpanVisitor(&mut data.span));
+            }
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        if *event.metadata().level() != Level::ERROR {
+            return;
+        }
+        if let Some(tracing_span) = ctx.event_span(event) {
+            if let Some(data) = tracing_span.extensions_mut().get_mut::<SpanData>() {
+                let mut error = ErrorInfo {
+                    r#type: "error".to_string(),
+                    msg: String::new(),
+                    stack: String::new(),
+                };
+                event.record(&mut ErrorVisitor(&mut error));
+                data.span.error = Some(error);
+            }
+        }
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let data = match ctx
+            .span(&id)
+            .and_then(|span| span.extensions_mut().remove::<SpanData>())
+        {
+            Some(data) => data,
+            None => return,
+        };
+
+        let mut span = data.span;
+        span.finish_with(self.client.clock());
+        let is_root = span.parent_id.is_none();
+
+        let mut finished = data.finished.lock().unwrap();
+        finished.push(span);
+        if is_root {
+            let trace = Trace {
+                id: u128::from(data.trace_id),
+                spans: std::mem::take(&mut *finished),
+                priority: Priority::AutoKeep,
+                origin: None,
+            };
+            let _ = self.client.send_trace(trace);
+        }
+    }
+}
+
+struct SpanVisitor<'a>(&'a mut Span);
+
+impl<'a> SpanVisitor<'a> {
+    fn http(&mut self) -> &mut HttpInfo {
+        self.0.http.get_or_insert_with(|| HttpInfo {
+            url: String::new(),
+            status_code: String::new(),
+            method: String::new(),
+            request_size: None,
+            response_size: None,
+            route: None,
+        })
+    }
+
+    fn sql(&mut self) -> &mut SqlInfo {
+        self.0.sql.get_or_insert_with(|| SqlInfo {
+            query: String::new(),
+            rows: String::new(),
+            db: String::new(),
+        })
+    }
+
+    fn messaging(&mut self) -> &mut MessagingInfo {
+        self.0.messaging.get_or_insert_with(|| MessagingInfo {
+            system: String::new(),
+            destination: String::new(),
+            operation: String::new(),
+        })
+    }
+
+    fn cache(&mut self) -> &mut CacheInfo {
+        self.0.cache.get_or_insert_with(|| CacheInfo {
+            system: String::new(),
+            command: String::new(),
+            key: String::new(),
+        })
+    }
+}
+
+impl<'a> Visit for SpanVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        let value = value.to_string();
+        match field.name() {
+            "resource" => self.0.resource = value,
+            "span.type" => self.0.r#type = value.into(),
+            "service" => self.0.service = Some(value),
+            "http.url" => self.http().url = value,
+            "http.method" => self.http().method = value,
+            "http.status_code" => self.http().status_code = value,
+            "http.route" => self.http().route = Some(value),
+            "http.request.content_length" => self.http().request_size = value.parse().ok(),
+            "http.response.content_length" => self.http().response_size = value.parse().ok(),
+            "sql.query" => self.sql().query = value,
+            "sql.rows" => self.sql().rows = value,
+            "sql.db" => self.sql().db = value,
+            "messaging.system" => self.messaging().system = value,
+            "messaging.destination" => self.messaging().destination = value,
+            "messaging.operation" => self.messaging().operation = value,
+            "cache.system" => self.cache().system = value,
+            "cache.command" => self.cache().command = value,
+            "cache.key" => self.cache().key = value,
+            name => {
+                self.0.tags.insert(name.to_string(), value);
+            }
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value))
+    }
+}
+
+struct ErrorVisitor<'a>(&'a mut ErrorInfo);
+
+impl<'a> Visit for ErrorVisitor<'a> {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        match field.name() {
+            "message" => self.0.msg = value.to_string(),
+            "error.type" => self.0.r#type = value.to_string(),
+            "error.stack" => self.0.stack = value.to_string(),
+            _ => {}
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.record_str(field, &format!("{:?}", value))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Config;
+    use crate::clock::MockClock;
+    use crate::testing::recording_client_with_clock;
+
+    use std::time::{Duration, UNIX_EPOCH};
+    use tracing_subscriber::layer::SubscriberExt;
+
+    #[tokio::test]
+    async fn test_layer_maps_spans_to_a_trace() {
+        let clock = MockClock::new(UNIX_EPOCH + Duration::from_secs(1_600_000_000));
+        let (client, connector) = recording_client_with_clock(
+            Config {
+                service: "service_name".to_string(),
+                ..Default::default()
+            },
+            Arc::new(clock.clone()),
+        );
+
+        let subscriber = tracing_subscriber::registry().with(DatadogLayer::new(client.clone()));
+        ::tracing::subscriber::with_default(subscriber, || {
+            let request = ::tracing::info_span!(
+                "request",
+                resource = "GET /path",
+                span.type = "web",
+                http.method = "GET",
+                http.url = "/path",
+                http.status_code = ::tracing::field::Empty,
+                http.response.content_length = 21u64,
+            );
+            let _request = request.enter();
+            {
+                let database =
+                    ::tracing::info_span!("database", sql.query = "select 1", db.instance = 2);
+                let _database = database.enter();
+                ::tracing::error!(error.type = "timeout", "query timed out");
+                clock.advance(Duration::from_millis(20));
+            }
+            clock.advance(Duration::from_millis(5));
+            request.record("http.status_code", "500");
+        });
+        client.flush().await;
+
+        let traces = connector.traces();
+        assert_eq!(traces.len(), 1);
+        let spans = traces[0].as_array().unwrap();
+        assert_eq!(spans.len(), 2);
+
+        let database = &spans[0];
+        let request = &spans[1];
+        assert_eq!(request["parent_id"], serde_json::Value::Null);
+        assert_eq!(database["parent_id"], request["span_id"]);
+        assert_eq!(database["trace_id"], request["trace_id"]);
+        assert_eq!(request["start"], 1_600_000_000_000_000_000u64);
+        assert_eq!(request["duration"], 25_000_000);
+        assert_eq!(database["start"], request["start"]);
+        assert_eq!(database["duration"], 20_000_000);
+
+        assert_eq!(request["service"], "service_name");
+        assert_eq!(request["name"], "request");
+        assert_eq!(request["resource"], "GET /path");
+        assert_eq!(request["type"], "web");
+        let meta = &request["meta"];
+        assert_eq!(meta["http.method"], "GET");
+        assert_eq!(meta["http.url"], "/path");
+        assert_eq!(meta["http.status_code"], "500");
+        assert_eq!(meta["http.response.content_length"], "21");
+        assert_eq!(meta.get("http.request.content_length"), None);
+        assert_eq!(request["error"], 0);
+
+        assert_eq!(database["resource"], "database");
+        let meta = &database["meta"];
+        assert_eq!(meta["sql.query"], "select 1");
+        assert_eq!(meta["db.instance"], "2");
+        assert_eq!(database["error"], 1);
+        assert_eq!(meta["error.type"], "timeout");
+        assert_eq!(meta["error.msg"], "query timed out");
+    }
+}